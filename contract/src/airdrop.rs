@@ -0,0 +1,149 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+const CLAIMS_PER_WORD: u32 = 128;
+
+// Tokens of the owner escrowed by the contract, claimable until `expires_at`.
+// Leaf `i` of the Merkle tree is sha256("<i>:<account_id>:<amount>"), `i` keeps track of the claims
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Airdrop {
+    id: u32,
+    root: Vec<u8>,
+    expires_at: u64,
+    total: Balance,
+    claimed: Balance,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AirdropInfo {
+    pub id: u32,
+    pub root: Base64VecU8,
+    pub expires_at: U64,
+    pub total: U128,
+    pub claimed: U128,
+}
+
+// One airdrop runs at a time. Claims are kept as bitmaps of 128 leaves, keyed by the
+// airdrop, so a new airdrop starts with no claims
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AirdropDistributor {
+    current: Option<Airdrop>,
+    next_id: u32,
+    claims: LookupMap<(u32, u32), u128>,
+}
+
+impl AirdropDistributor {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self { current: None, next_id: 0, claims: LookupMap::new(prefix) }
+    }
+
+    // Tokens of the running airdrop not claimed yet
+    pub fn tokens_escrowed(&self) -> Balance {
+        self.current.as_ref().map_or(0, |airdrop| airdrop.total - airdrop.claimed)
+    }
+
+    fn is_claimed(&self, id: u32, index: u32) -> bool {
+        let word = self.claims.get(&(id, index / CLAIMS_PER_WORD)).unwrap_or(0);
+        word & (1 << (index % CLAIMS_PER_WORD)) != 0
+    }
+
+    fn set_claimed(&mut self, id: u32, index: u32) {
+        let key = (id, index / CLAIMS_PER_WORD);
+        let word = self.claims.get(&key).unwrap_or(0);
+        self.claims.insert(&key, &(word | (1 << (index % CLAIMS_PER_WORD))));
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_airdrop(&self) -> Option<AirdropInfo> {
+        self.airdrops.current.as_ref().map(|airdrop| AirdropInfo {
+            id: airdrop.id,
+            root: airdrop.root.clone().into(),
+            expires_at: airdrop.expires_at.into(),
+            total: airdrop.total.into(),
+            claimed: airdrop.claimed.into(),
+        })
+    }
+
+    pub fn is_airdrop_claimed(&self, index: u32) -> bool {
+        self.airdrops.current.as_ref().map_or(false, |airdrop| self.airdrops.is_claimed(airdrop.id, index))
+    }
+
+    // Escrow `amount` tokens of the owner, claimable with proofs of `root` until `expires_at`.
+    // Moving the owner's tokens requires a full access key, by attaching 1 yoctoNEAR
+    #[payable]
+    pub fn start_airdrop(&mut self, root: Base64VecU8, amount: U128, expires_at: U64) -> u32 {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(self.airdrops.current.is_none(), "An airdrop is running, close it first");
+
+        let root: Vec<u8> = root.into();
+        merkle::assert_valid_root(&root);
+        assert!(amount.0 > 0, "The airdrop must have tokens");
+        assert!(expires_at.0 > env::block_timestamp(), "The airdrop must expire in the future");
+
+        let owner_id = self.owner_id.clone();
+        self.internal_transfer(&owner_id, &env::current_account_id(), amount.0, Some("airdrop".to_string()));
+
+        let id = self.airdrops.next_id;
+        self.airdrops.next_id += 1;
+        self.airdrops.current = Some(Airdrop { id, root, expires_at: expires_at.0, total: amount.0, claimed: 0 });
+        id
+    }
+
+    // Claim the `amount` tokens of leaf `index`. Accounts without storage attach the
+    // storage deposit and are registered, the rest of the deposit is returned
+    #[payable]
+    pub fn claim_airdrop(&mut self, index: u32, amount: U128, proof: Vec<Base64VecU8>) -> U128 {
+        let user: AccountId = env::predecessor_account_id();
+        let airdrop = self.airdrops.current.as_ref().expect("There is no airdrop running");
+        let id = airdrop.id;
+
+        assert!(env::block_timestamp() < airdrop.expires_at, "The airdrop expired");
+        assert!(!self.airdrops.is_claimed(id, index), "The airdrop was already claimed");
+        let leaf = env::sha256(format!("{}:{}:{}", index, &user, amount.0).as_bytes());
+        assert!(merkle::verify(leaf, proof, &airdrop.root), "Invalid airdrop proof");
+        assert!(airdrop.claimed + amount.0 <= airdrop.total, "The airdrop does not have enough tokens left");
+
+        let mut deposit = env::attached_deposit();
+        if !self.token.accounts.contains_key(&user) {
+            let storage_cost = Balance::from(self.token.account_storage_usage) * env::storage_byte_cost();
+            assert!(deposit >= storage_cost, "Attach {} yoctoNEAR to register the account", storage_cost);
+            self.token.internal_register_account(&user);
+            deposit -= storage_cost;
+        }
+        if deposit > 0 {
+            self.refund(user.clone(), deposit);
+        }
+
+        self.airdrops.set_claimed(id, index);
+        if let Some(airdrop) = self.airdrops.current.as_mut() {
+            airdrop.claimed += amount.0;
+        }
+        self.internal_transfer(&env::current_account_id(), &user, amount.0, Some("airdrop".to_string()));
+        amount
+    }
+
+    // After the expiry, anyone can send the unclaimed tokens back to the owner
+    pub fn close_airdrop(&mut self) -> U128 {
+        let airdrop = self.airdrops.current.as_ref().expect("There is no airdrop running");
+        assert!(env::block_timestamp() >= airdrop.expires_at, "The airdrop did not expire yet");
+
+        let unclaimed = self.airdrops.tokens_escrowed();
+        self.airdrops.current = None;
+
+        let owner_id = self.owner_id.clone();
+        if unclaimed > 0 {
+            self.internal_transfer(&env::current_account_id(), &owner_id, unclaimed, Some("airdrop".to_string()));
+        }
+        log!("Airdrop closed, {} tokens returned to {}", unclaimed, &owner_id);
+        unclaimed.into()
+    }
+}
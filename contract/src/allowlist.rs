@@ -0,0 +1,87 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{Base64VecU8, ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+// Until `public_sale_at`, only the accounts in the Merkle tree of `root` can buy tokens.
+// Each leaf is sha256("<account_id>:<allocation>"), with the allocation in yoctoNEAR
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct AllowlistSale {
+    root: Option<Vec<u8>>,
+    public_sale_at: u64,
+}
+
+// Passed by the buyers to `exchange_near_for_tokens` while the allowlist phase lasts
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AllowlistProof {
+    pub allocation: U128,
+    pub proof: Vec<Base64VecU8>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AllowlistStatus {
+    pub root: Option<Base64VecU8>,
+    pub public_sale_at: U64,
+    pub is_public: bool,
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_allowlist(&self) -> AllowlistStatus {
+        AllowlistStatus {
+            root: self.allowlist.root.clone().map(Base64VecU8::from),
+            public_sale_at: self.allowlist.public_sale_at.into(),
+            is_public: self.is_public_sale(),
+        }
+    }
+
+    // NEAR of its allocation that `account_id` already spent
+    pub fn get_allowlist_used(&self, account_id: ValidAccountId) -> U128 {
+        self.allowlist_used.get(account_id.as_ref()).unwrap_or(0).into()
+    }
+
+    // Restrict the sales to the allowlist of `root` until `public_sale_at`, in nanoseconds
+    pub fn set_allowlist(&mut self, root: Base64VecU8, public_sale_at: U64) {
+        self.assert_owner();
+
+        let root: Vec<u8> = root.into();
+        merkle::assert_valid_root(&root);
+
+        log!("Sales are restricted to the allowlist until {}", public_sale_at.0);
+        self.allowlist = AllowlistSale { root: Some(root), public_sale_at: public_sale_at.into() };
+    }
+}
+
+impl Contract {
+    fn is_public_sale(&self) -> bool {
+        self.allowlist.root.is_none() || env::block_timestamp() >= self.allowlist.public_sale_at
+    }
+
+    // Check that `user` is in the allowlist, and spend `near_amount` of its allocation
+    pub(crate) fn use_allowlist(&mut self, user: &AccountId, near_amount: Balance, proof: Option<AllowlistProof>) {
+        if self.is_public_sale() {
+            return;
+        }
+
+        let proof = proof.expect("Only the allowlist can buy tokens yet, a proof is required");
+        let leaf = env::sha256(format!("{}:{}", user, proof.allocation.0).as_bytes());
+        let root = self.allowlist.root.as_ref().expect("There is no allowlist");
+        assert!(merkle::verify(leaf, proof.proof, root), "Invalid allowlist proof");
+
+        let used = self.allowlist_used.get(user).unwrap_or(0);
+        assert!(used + near_amount <= proof.allocation.0,
+                "The purchase exceeds the allowlist allocation, {} yoctoNEAR left", proof.allocation.0 - used);
+        self.allowlist_used.insert(user, &(used + near_amount));
+    }
+
+    // Give back the allocation spent by a purchase that did not take place
+    pub(crate) fn release_allowlist(&mut self, user: &AccountId, near_amount: Balance) {
+        if let Some(used) = self.allowlist_used.get(user) {
+            self.allowlist_used.insert(user, &used.saturating_sub(near_amount));
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Gas};
+
+use crate::*;
+
+const MAX_PREPAID_GAS: Gas = 300 * TGAS;
+const TIME_THRESHOLD: u64 = 300_000_000_000; // 5 minutes
+const MAX_TIME_THRESHOLD: u64 = 86_400_000_000_000; // 1 day
+
+// Gas used by callbacks that only check the results of Pool Party, besides the gas they attach
+pub(crate) const CHECK_CALLBACK_GAS: Gas = 10 * TGAS;
+
+// Gas attached to each cross-contract call, and the gas each exchange requires
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    // Exchanges close this many nanoseconds before the raffle
+    pub time_threshold: U64,
+    pub get_pool_info_gas: U64,
+    pub cache_callback_gas: U64,
+    // Updates the cache and settles the queue, besides the gas of the exchange that waited for it
+    pub refresh_callback_gas: U64,
+    pub give_from_reserve_gas: U64,
+    pub tickets_callback_gas: U64,
+    pub tokens_for_tickets_prepaid_gas: U64,
+    pub deposit_and_stake_gas: U64,
+    pub near_callback_gas: U64,
+    pub near_for_tokens_prepaid_gas: U64,
+    // Attached by the callbacks that send NEAR back, to record failed transfers
+    pub refund_callback_gas: U64,
+    pub unstake_gas: U64,
+    pub redeem_callback_gas: U64,
+    pub redeem_prepaid_gas: U64,
+    pub withdraw_all_gas: U64,
+    pub withdraw_callback_gas: U64,
+    pub withdraw_prepaid_gas: U64,
+    // Fee on token sales, in basis points of the attached NEAR. It is sent to the
+    // fee recipient, or kept by the contract until the owner withdraws it
+    pub sale_fee_bps: u32,
+    pub fee_recipient: Option<AccountId>,
+    // Limits on the tokens sold, in each raffle period. None means no limit
+    pub max_tokens_per_account: Option<U128>,
+    pub max_tokens_per_period: Option<U128>,
+    pub min_purchase: Option<U128>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            time_threshold: TIME_THRESHOLD.into(),
+            get_pool_info_gas: (20 * TGAS).into(),
+            cache_callback_gas: (5 * TGAS).into(),
+            refresh_callback_gas: (20 * TGAS).into(),
+            give_from_reserve_gas: (120 * TGAS).into(),
+            tickets_callback_gas: (50 * TGAS).into(),
+            tokens_for_tickets_prepaid_gas: (190 * TGAS).into(),
+            deposit_and_stake_gas: (190 * TGAS).into(),
+            near_callback_gas: (20 * TGAS).into(),
+            near_for_tokens_prepaid_gas: (230 * TGAS).into(),
+            refund_callback_gas: (5 * TGAS).into(),
+            unstake_gas: (50 * TGAS).into(),
+            redeem_callback_gas: (20 * TGAS).into(),
+            redeem_prepaid_gas: (120 * TGAS).into(),
+            withdraw_all_gas: (50 * TGAS).into(),
+            withdraw_callback_gas: (20 * TGAS).into(),
+            withdraw_prepaid_gas: (150 * TGAS).into(),
+            sale_fee_bps: 0,
+            fee_recipient: None,
+            max_tokens_per_account: None,
+            max_tokens_per_period: None,
+            min_purchase: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn assert_valid(&self) {
+        assert!(self.time_threshold.0 <= MAX_TIME_THRESHOLD, "The time threshold cannot be longer than a day");
+        assert!(self.sale_fee_bps <= fees::MAX_SALE_FEE_BPS,
+                "The sale fee cannot be higher than {} basis points", fees::MAX_SALE_FEE_BPS);
+        if let Some(fee_recipient) = &self.fee_recipient {
+            assert!(env::is_valid_account_id(fee_recipient.as_bytes()), "The fee recipient is not a valid account");
+        }
+
+        let min_purchase = self.min_purchase.map_or(0, |min| min.0);
+        for max in self.max_tokens_per_account.iter().chain(self.max_tokens_per_period.iter()) {
+            assert!(max.0 >= min_purchase, "The purchase limits cannot be lower than the minimum purchase");
+        }
+
+        for gas in &[
+            self.get_pool_info_gas,
+            self.cache_callback_gas,
+            self.refresh_callback_gas,
+            self.give_from_reserve_gas,
+            self.tickets_callback_gas,
+            self.deposit_and_stake_gas,
+            self.near_callback_gas,
+            self.refund_callback_gas,
+            self.unstake_gas,
+            self.redeem_callback_gas,
+            self.withdraw_all_gas,
+            self.withdraw_callback_gas,
+        ] {
+            assert!(gas.0 > 0, "The gas attached to a call cannot be zero");
+        }
+
+        assert!(self.get_pool_info_gas.0 + self.cache_callback_gas.0 <= MAX_PREPAID_GAS,
+                "Caching the reserve would need more than {} gas", MAX_PREPAID_GAS);
+
+        assert!(self.give_from_reserve_gas.0 + self.tickets_callback_gas.0 <= self.tokens_for_tickets_prepaid_gas.0,
+                "The gas attached when exchanging tokens for tickets exceeds the required prepaid gas");
+        assert!(self.deposit_and_stake_gas.0 + self.near_callback_gas.0 <= self.near_for_tokens_prepaid_gas.0,
+                "The gas attached when exchanging NEAR for tokens exceeds the required prepaid gas");
+
+        assert!(self.refund_callback_gas.0 < self.near_callback_gas.0,
+                "The NEAR exchange callback needs more gas than the refund callback it attaches");
+        assert!(self.refund_callback_gas.0 < self.withdraw_callback_gas.0,
+                "The withdraw callback needs more gas than the refund callback it attaches");
+
+        // Redemptions unstake from Pool Party, and later check it before withdrawing
+        let unstake_check_gas = self.get_pool_info_gas.0 + CHECK_CALLBACK_GAS;
+        assert!(self.unstake_gas.0 + unstake_check_gas + self.redeem_callback_gas.0 <= self.redeem_prepaid_gas.0,
+                "The gas attached when redeeming tokens for NEAR exceeds the required prepaid gas");
+        let check_gas = 2 * self.get_pool_info_gas.0 + CHECK_CALLBACK_GAS;
+        assert!(check_gas + self.withdraw_all_gas.0 + self.withdraw_callback_gas.0 <= self.withdraw_prepaid_gas.0,
+                "The gas attached when withdrawing redeemed NEAR exceeds the required prepaid gas");
+        assert!(self.redeem_prepaid_gas.0 <= MAX_PREPAID_GAS && self.withdraw_prepaid_gas.0 <= MAX_PREPAID_GAS,
+                "The required prepaid gas cannot exceed {}", MAX_PREPAID_GAS);
+
+        // Exchanges with an outdated cache first ask Pool Party for the new reserve
+        assert!(self.refresh_callback_gas.0 >= self.cache_callback_gas.0 + queue::SETTLE_GAS,
+                "The refresh callback needs more gas than caching the reserve and settling the exchange");
+        let refresh_gas = self.get_pool_info_gas.0 + self.refresh_callback_gas.0;
+        assert!(self.tokens_for_tickets_prepaid_gas.0 + refresh_gas <= MAX_PREPAID_GAS
+                && self.near_for_tokens_prepaid_gas.0 + refresh_gas <= MAX_PREPAID_GAS,
+                "The required prepaid gas cannot exceed {}", MAX_PREPAID_GAS);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_config(&self) -> Config {
+        self.config.clone()
+    }
+
+    pub fn update_config(&mut self, config: Config) {
+        self.assert_owner();
+        config.assert_valid();
+        events::config_update(&config);
+        self.config = config;
+    }
+}
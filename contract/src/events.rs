@@ -0,0 +1,172 @@
+//! NEP-297 events, logged as `EVENT_JSON:{...}`
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{env, AccountId, Balance};
+
+use crate::{
+    Config, ExchangeDirection, PauseStatus, PendingOperation, PendingRedemption, QueuedExchange, WithdrawalInProgress,
+};
+
+const NEP141_STANDARD: &str = "nep141";
+const NEP141_VERSION: &str = "1.0.0";
+const POOL_STANDARD: &str = "poolparty_token";
+const POOL_VERSION: &str = "1.0.0";
+
+fn emit(standard: &str, version: &str, event: &str, data: Value) {
+    let event = json!({
+        "standard": standard,
+        "version": version,
+        "event": event,
+        "data": [data],
+    });
+    env::log(format!("EVENT_JSON:{}", event).as_bytes());
+}
+
+fn with_memo(mut data: Value, memo: Option<&str>) -> Value {
+    if let Some(memo) = memo {
+        data["memo"] = json!(memo);
+    }
+    data
+}
+
+// NEP-141 events
+
+pub fn ft_mint(owner_id: &AccountId, amount: Balance, memo: Option<&str>) {
+    let data = json!({ "owner_id": owner_id, "amount": amount.to_string() });
+    emit(NEP141_STANDARD, NEP141_VERSION, "ft_mint", with_memo(data, memo));
+}
+
+pub fn ft_transfer(old_owner_id: &AccountId, new_owner_id: &AccountId, amount: Balance, memo: Option<&str>) {
+    let data = json!({
+        "old_owner_id": old_owner_id,
+        "new_owner_id": new_owner_id,
+        "amount": amount.to_string(),
+    });
+    emit(NEP141_STANDARD, NEP141_VERSION, "ft_transfer", with_memo(data, memo));
+}
+
+pub fn ft_burn(owner_id: &AccountId, amount: Balance, memo: Option<&str>) {
+    let data = json!({ "owner_id": owner_id, "amount": amount.to_string() });
+    emit(NEP141_STANDARD, NEP141_VERSION, "ft_burn", with_memo(data, memo));
+}
+
+// Pool Party token events
+
+// `near_amount` is the NEAR staked, the `fee_amount` was paid on top of it.
+// Prices are in yoctoNEAR per token unit, the marginal one after the exchange
+pub fn exchange_near_for_tokens(
+    account_id: &AccountId,
+    near_amount: Balance,
+    token_amount: Balance,
+    fee_amount: Balance,
+    marginal_price: Balance,
+) {
+    let data = json!({
+        "account_id": account_id,
+        "near_amount": near_amount.to_string(),
+        "token_amount": token_amount.to_string(),
+        "fee_amount": fee_amount.to_string(),
+        "average_price": near_amount.checked_div(token_amount).unwrap_or(0).to_string(),
+        "marginal_price": marginal_price.to_string(),
+    });
+    emit(POOL_STANDARD, POOL_VERSION, "exchange_near_for_tokens", data);
+}
+
+pub fn exchange_near_for_tokens_refund(account_id: &AccountId, near_amount: Balance) {
+    let data = json!({ "account_id": account_id, "near_amount": near_amount.to_string() });
+    emit(POOL_STANDARD, POOL_VERSION, "exchange_near_for_tokens_refund", data);
+}
+
+pub fn exchange_tokens_for_tickets(account_id: &AccountId, token_amount: Balance, ticket_amount: Balance) {
+    let data = json!({
+        "account_id": account_id,
+        "token_amount": token_amount.to_string(),
+        "ticket_amount": ticket_amount.to_string(),
+    });
+    emit(POOL_STANDARD, POOL_VERSION, "exchange_tokens_for_tickets", data);
+}
+
+pub fn exchange_tokens_for_tickets_refund(account_id: &AccountId, token_amount: Balance, ticket_amount: Balance) {
+    let data = json!({
+        "account_id": account_id,
+        "token_amount": token_amount.to_string(),
+        "ticket_amount": ticket_amount.to_string(),
+    });
+    emit(POOL_STANDARD, POOL_VERSION, "exchange_tokens_for_tickets_refund", data);
+}
+
+pub fn redeem_tokens_for_near(account_id: &AccountId, token_amount: Balance, near_amount: Balance) {
+    let data = json!({
+        "account_id": account_id,
+        "token_amount": token_amount.to_string(),
+        "near_amount": near_amount.to_string(),
+    });
+    emit(POOL_STANDARD, POOL_VERSION, "redeem_tokens_for_near", data);
+}
+
+pub fn redeem_tokens_for_near_refund(account_id: &AccountId, token_amount: Balance, near_amount: Balance) {
+    let data = json!({
+        "account_id": account_id,
+        "token_amount": token_amount.to_string(),
+        "near_amount": near_amount.to_string(),
+    });
+    emit(POOL_STANDARD, POOL_VERSION, "redeem_tokens_for_near_refund", data);
+}
+
+pub fn redeemed_near_withdrawn(account_id: &AccountId, near_amount: Balance) {
+    let data = json!({ "account_id": account_id, "near_amount": near_amount.to_string() });
+    emit(POOL_STANDARD, POOL_VERSION, "redeemed_near_withdrawn", data);
+}
+
+pub fn reserve_cache_update(reserve: Balance, next_raffle: u64) {
+    let data = json!({ "reserve": reserve.to_string(), "next_raffle": next_raffle.to_string() });
+    emit(POOL_STANDARD, POOL_VERSION, "reserve_cache_update", data);
+}
+
+pub fn refund_recorded(account_id: &AccountId, near_amount: Balance) {
+    let data = json!({ "account_id": account_id, "near_amount": near_amount.to_string() });
+    emit(POOL_STANDARD, POOL_VERSION, "refund_recorded", data);
+}
+
+pub fn refund_claimed(account_id: &AccountId, near_amount: Balance) {
+    let data = json!({ "account_id": account_id, "near_amount": near_amount.to_string() });
+    emit(POOL_STANDARD, POOL_VERSION, "refund_claimed", data);
+}
+
+pub fn reconcile_pending(operation: &PendingOperation, succeeded: bool) {
+    let data = json!({ "operation": operation, "succeeded": succeeded });
+    emit(POOL_STANDARD, POOL_VERSION, "reconcile_pending", data);
+}
+
+pub fn reconcile_redemption(redemption: &PendingRedemption, succeeded: bool) {
+    let data = json!({ "redemption": redemption, "succeeded": succeeded });
+    emit(POOL_STANDARD, POOL_VERSION, "reconcile_redemption", data);
+}
+
+pub fn reconcile_withdrawal(withdrawal: &WithdrawalInProgress, near_withdrawn: Balance) {
+    let data = json!({ "withdrawal": withdrawal, "near_withdrawn": near_withdrawn.to_string() });
+    emit(POOL_STANDARD, POOL_VERSION, "reconcile_withdrawal", data);
+}
+
+pub fn exchange_queued(entry: &QueuedExchange) {
+    emit(POOL_STANDARD, POOL_VERSION, "exchange_queued", json!(entry));
+}
+
+pub fn queued_exchange_removed(entry: &QueuedExchange, reason: &str) {
+    let data = json!({ "exchange": entry, "reason": reason });
+    emit(POOL_STANDARD, POOL_VERSION, "queued_exchange_removed", data);
+}
+
+// `direction` is None when both directions changed, `status` is the state after the change
+pub fn paused(direction: Option<ExchangeDirection>, status: &PauseStatus) {
+    let data = json!({ "direction": direction, "status": status });
+    emit(POOL_STANDARD, POOL_VERSION, "paused", data);
+}
+
+pub fn unpaused(direction: Option<ExchangeDirection>, status: &PauseStatus) {
+    let data = json!({ "direction": direction, "status": status });
+    emit(POOL_STANDARD, POOL_VERSION, "unpaused", data);
+}
+
+pub fn config_update(config: &Config) {
+    emit(POOL_STANDARD, POOL_VERSION, "config_update", json!(config));
+}
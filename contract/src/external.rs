@@ -1,7 +1,10 @@
 use near_sdk::json_types::{U64, U128};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::{ext_contract, log, near_bindgen, env, PromiseResult};
+use near_sdk::{ext_contract, near_bindgen, env, serde_json, AccountId, PromiseOrValue, PromiseResult};
+use near_sdk::serde::de::DeserializeOwned;
 use near_sdk::serde::{Deserialize, Serialize};
+use std::fmt;
+
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize)]
@@ -14,32 +17,94 @@ pub struct PoolInfo {
   pub withdraw_ready: bool
 }
 
+// Balances of an account in Pool Party, as returned by `get_account`
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolAccount {
+  pub account_id: AccountId,
+  // Tickets of the account, they take part in the raffle
+  pub staked_balance: U128,
+  // NEAR being unstaked, it can be withdrawn once `available`
+  pub unstaked_balance: U128,
+  // Epoch from which the unstaked NEAR can be withdrawn
+  pub available_when: u64,
+  pub available: bool
+}
+
 #[ext_contract(this_contract)]
 trait Callbacks {
-  fn exchange_tokens_for_tickets_callback(&mut self, user: AccountId, tokens: Balance, tickets:Balance) -> bool;
-  fn exchange_near_for_tokens_callback(&mut self, user: AccountId, tokens: Balance, tickets: Balance) -> bool ;
-  fn cache_pool_party_reserve_callback(&mut self);  
+  fn exchange_tokens_for_tickets_callback(&mut self, operation_id: u64, user: AccountId, tokens: Balance, tickets:Balance) -> bool;
+  fn redeem_tickets_callback(&mut self, operation_id: u64, user: AccountId, tokens: Balance, tickets: Balance) -> U128;
+  fn exchange_near_for_tokens_callback(&mut self, operation_id: u64, user: AccountId, tokens: Balance, tickets: Balance,
+                                       fee: Balance) -> bool ;
+  fn cache_pool_party_reserve_callback(&mut self);
+  fn refund_callback(&mut self, user: AccountId, amount: Balance) -> bool;
+  fn exchange_after_refresh_callback(&mut self, queued_id: u64) -> u64;
+  fn redeem_tokens_for_near_callback(&mut self, redemption_id: u64) -> PromiseOrValue<bool>;
+  fn redeem_unstaked_callback(&mut self, redemption_id: u64) -> bool;
+  fn withdraw_redeemed_callback(&mut self, withdrawal_id: u64) -> PromiseOrValue<bool>;
+  fn withdraw_all_callback(&mut self, withdrawal_id: u64, unstaked: U128) -> PromiseOrValue<bool>;
 }
 
 // Pool Party interface, so we can do async calls
 #[ext_contract(poolparty_contract)]
 trait PoolParty {
+    // Views
+    fn get_account(&self, account_id: AccountId) -> PoolAccount;
+    fn get_pool_info(&self) -> PoolInfo;
+
+    // Staking: the attached NEAR becomes tickets of the caller
     #[payable]
     fn deposit_and_stake(&mut self) -> bool;
-    fn get_pool_info(&self) -> PoolInfo;
+
+    // Unstaking: tickets of the caller become NEAR, which can be withdrawn a few epochs later
+    fn unstake(&mut self, amount: U128) -> bool;
+    fn withdraw_all(&mut self);
+
+    // Prizes: give tickets from the reserve, and pick the winner of the raffle
     fn give_from_reserve(&self, to: AccountId, amount:U128);
+    fn raffle(&mut self) -> AccountId;
 }
 
 // Aux functions to interact with pool party
 
-pub fn did_promise_succeded() -> bool {
-  if env::promise_results_count() != 1 {
-    log!("Expected a result on the callback");
-    return false;
+// Why the result of a promise could not be used on a callback
+#[derive(Debug, PartialEq)]
+pub enum PromiseError {
+  // The callback did not receive a result at the given index
+  Missing,
+  NotReady,
+  Failed,
+  // The call succeeded, but the returned value is not what we expected
+  Undecodable(String),
+}
+
+impl fmt::Display for PromiseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PromiseError::Missing => write!(f, "no result was received"),
+      PromiseError::NotReady => write!(f, "the result is not ready"),
+      PromiseError::Failed => write!(f, "the call failed"),
+      PromiseError::Undecodable(error) => write!(f, "the result could not be decoded ({})", error),
+    }
+  }
+}
+
+// Raw value returned by the promise at `index`, for calls where only success matters
+pub fn promise_result(index: u64) -> Result<Vec<u8>, PromiseError> {
+  if index >= env::promise_results_count() {
+    return Err(PromiseError::Missing);
   }
 
-  match env::promise_result(0) {
-      PromiseResult::Successful(_) => true,
-      _ => false,
+  match env::promise_result(index) {
+    PromiseResult::Successful(value) => Ok(value),
+    PromiseResult::NotReady => Err(PromiseError::NotReady),
+    PromiseResult::Failed => Err(PromiseError::Failed),
   }
 }
+
+// Value returned by the promise at `index`, decoded from JSON
+pub fn promise_result_json<T: DeserializeOwned>(index: u64) -> Result<T, PromiseError> {
+  let value = promise_result(index)?;
+  serde_json::from_slice(&value).map_err(|error| PromiseError::Undecodable(error.to_string()))
+}
@@ -0,0 +1,50 @@
+use near_sdk::json_types::U128;
+use near_sdk::{log, near_bindgen, AccountId, Balance, Promise};
+
+use crate::*;
+
+pub(crate) const MAX_SALE_FEE_BPS: u32 = 1_000; // 10%
+const BPS_DENOMINATOR: u32 = 10_000;
+
+#[near_bindgen]
+impl Contract {
+    // Sale fees kept by the contract while there is no fee recipient
+    pub fn get_accrued_fees(&self) -> U128 {
+        self.fees_accrued.into()
+    }
+
+    pub fn withdraw_fees(&mut self) -> Promise {
+        self.assert_owner();
+
+        let amount = self.fees_accrued;
+        assert!(amount > 0, "There are no fees to withdraw");
+        self.fees_accrued = 0;
+
+        let receiver: AccountId = self.config.fee_recipient.clone().unwrap_or_else(|| self.owner_id.clone());
+        log!("Withdrawing {} yoctoNEAR of fees to {}", amount, &receiver);
+        self.refund(receiver, amount)
+    }
+}
+
+impl Contract {
+    // Part of the NEAR paid for tokens that is not staked
+    pub(crate) fn sale_fee(&self, near_amount: Balance) -> Balance {
+        let fee: U256 = U256::from(near_amount) * U256::from(self.config.sale_fee_bps) / U256::from(BPS_DENOMINATOR);
+        u256_to_balance(fee)
+    }
+
+    // Send the fee of a completed sale to the fee recipient, or keep it until `withdraw_fees`
+    pub(crate) fn collect_fee(&mut self, fee: Balance) {
+        if fee == 0 {
+            return;
+        }
+
+        self.treasury.record_fee(fee);
+        match self.config.fee_recipient.clone() {
+            Some(fee_recipient) => {
+                self.refund(fee_recipient, fee);
+            }
+            None => self.fees_accrued += fee,
+        }
+    }
+}
@@ -2,11 +2,13 @@
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
 };
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::fungible_token::FungibleToken;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LazyOption;
-use near_sdk::json_types::{ValidAccountId, U128};
-use near_sdk::{env, log, PromiseResult, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, Promise, PromiseOrValue};
+use near_sdk::collections::{LazyOption, LookupMap};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, Promise, PromiseOrValue};
 use uint::construct_uint;
 
 construct_uint! {
@@ -15,51 +17,170 @@ construct_uint! {
 }
 
 pub mod external;
+mod airdrop;
+mod allowlist;
+mod config;
+mod events;
+mod fees;
+mod limits;
+mod merkle;
+mod owner;
+mod pause;
+mod pending;
+mod pricing;
+mod queue;
+mod receiver;
+mod recovery;
+mod redemption;
+mod refresh;
+mod treasury;
+mod vesting;
+mod views;
 
 
-pub use crate::external::{this_contract, poolparty_contract, PoolInfo};
+pub use crate::airdrop::AirdropInfo;
+use crate::airdrop::AirdropDistributor;
+pub use crate::allowlist::{AllowlistProof, AllowlistStatus};
+use crate::allowlist::AllowlistSale;
+pub use crate::external::{this_contract, poolparty_contract, PoolAccount, PoolInfo};
+pub use crate::config::Config;
+pub use crate::limits::PurchaseAllowance;
+use crate::limits::PeriodPurchases;
+pub use crate::pause::{ExchangeDirection, PauseStatus};
+pub use crate::pending::PendingOperation;
+use crate::pending::PendingLedger;
+pub use crate::pricing::PricingMode;
+pub use crate::queue::QueuedExchange;
+use crate::queue::ExchangeQueue;
+pub use crate::receiver::TransferMessage;
+pub use crate::redemption::{PendingRedemption, PendingWithdrawal, WithdrawalInProgress};
+use crate::redemption::RedemptionLedger;
+pub use crate::treasury::TreasuryStats;
+pub use crate::vesting::VestingInfo;
+use crate::vesting::VestingLedger;
+pub use crate::views::{ExchangeState, Quote};
 
 near_sdk::setup_alloc!();
 
+fn u256_to_balance(value: U256) -> Balance {
+    assert!(value <= U256::from(u128::MAX), "The result does not fit in a u128");
+    value.as_u128()
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
     token: FungibleToken,
     metadata: LazyOption<FungibleTokenMetadata>,
+    owner_id: AccountId,
+    proposed_owner_id: Option<AccountId>,
+    pool_party_account: AccountId,
     pool_party_reserve: u128,
-    pool_party_next_raffle: u64,
+    // None until the reserve of Pool Party is cached for the first time
+    pool_party_next_raffle: Option<u64>,
+    pool_party_reserve_updated_at: u64,
+    paused: PauseStatus,
+    config: Config,
+    pending: PendingLedger,
+    claimable_refunds: LookupMap<AccountId, Balance>,
+    queue: ExchangeQueue,
+    redemptions: RedemptionLedger,
+    treasury: TreasuryStats,
+    fees_accrued: Balance,
+    pricing: PricingMode,
+    purchases: LookupMap<AccountId, PeriodPurchases>,
+    period_sales: PeriodPurchases,
+    allowlist: AllowlistSale,
+    allowlist_used: LookupMap<AccountId, Balance>,
+    airdrops: AirdropDistributor,
+    vesting: VestingLedger,
 }
 
 const DATA_IMAGE_SVG_NEAR_ICON: &str = "data:image/svg+xml,<svg viewBox='0 0 17.81 17.954' xmlns='http://www.w3.org/2000/svg' <g transform='translate(-8.4817 -206.38)'><g transform='translate(-131.99 168.02)'><g transform='matrix(.16504 0 0 .16504 -29.513 -136.23)'><path class='st26' d='m1136.9 1102.3 0.1087 0.7845c-0.2986-1.3087-0.5707-4.7987-3.1762-11.482l0.2905 0.8026c-3.27-7.517-6.2674-12.946-12.124-18.703 0.049 0.048 0.102 0.092 0.1496 0.1413-3.5617-3.4055-5.5545-5.1898-10.808-8.445l0.2239 0.1315c-4.1656-1.8953-11.893-7.4158-26.924-7.6325h0.4904c-0.6298 0.048-8.6208-0.5124-18.364 2.798l0.073-0.027c-0.1044 0.038-2.2771 0.8257-2.1729 0.7874-10.814 4.1862-14.868 8.7213-20.211 13.629-8.9441 10.017-11.324 18.883-11.289 18.783-8.8448 24.792 1.5598 56.016 31.729 68.136 0.9618 0.086 27.838 12.916 55.54-10.797 0.1125-0.193 21.713-18.833 16.465-48.905zm-1.4388-5.8871c0.099 0.3423 0.1906 0.681 0.2812 1.0191-0.1262-0.4389-0.1904-0.7088-0.2812-1.0191zm-0.1259-0.4192c-0.1166-0.3876-0.2407-0.7611-0.3629-1.1382-0.4448-1.4365-0.038-0.2095 0.3629 1.1382z' fill='#f5dc13'/><path class='st27' d='m1080.2 1058c11.006-0.8868 22.481 2.2354 31.087 7.37 5.3935 3.218 8.1741 5.8922 11.127 8.7611 18.988 19.497-16.91 71.924-54.451 79.726 37.323-7.7561 73.519-60.147 54.451-79.726 46.398 48.052-21.17 125.61-74.892 77.178-12.465-11.237-19.094-29.059-17.144-45.274 3.183-26.472 23.325-45.899 49.822-48.034z' fill='none'/><path class='st28' d='m1065.6 1152.7c0.3903-0.5048 1.262-0.5531 1.8918-0.4665-4.8334-18.374-2.0456-50.572 6.4826-73.168 5.378-14.249 11.793-21.379 17.218-20.644-19.109-2.1578-37.645 4.7318-49.881 20.392-17.098 22.196-5.4359 57.904 24.289 73.886z' fill='#f47638'/><path class='st29' d='m1065.9 1154.2c-0.5161-0.5629-0.6039-1.1274-0.3034-1.5162-30.046-16.155-41.219-51.908-24.289-73.886-2.567 3.6136-4.7987 6.4836-7.7446 13.899-4.4474 13.367-4.6159 23.266-0.7547 35.93 8.9613 24.109 19.954 26.857 33.092 25.574z' fill='#4599d4'/><path class='st26' d='m1068.1 1155.4c-0.799-0.1834-1.6486-0.6053-2.1909-1.1971-6.9651 0.587-14.966 0.6354-21.772-6.2624 0.3198 0.3192 2.4555 2.7554 5.8885 5.5635 11.951 9.2159 22.86 10.883 21.858 10.643-1.883-0.4264-3.2279-3.2916-3.7838-8.7469z' fill='#f5dc13'/><path class='st28' d='m1070.2 1155.2c-0.5285 0.4156-1.4437 0.3814-2.109 0.2288 0.5559 5.4553 1.9008 8.3205 3.7838 8.7469 12.558 2.5892 23.464 1.3104 34.303-3.4734 7.7317-3.8474 11.164-6.8024 11.064-6.7351-12.008 9.4899-29.964 10.486-47.041 1.2328z' fill='#f47638'/><path class='st29' d='m1070.3 1153.7c0.3845 0.5592 0.3469 1.0924-0.073 1.4226 17.077 9.2532 35.033 8.2571 47.041-1.2328 11.643-9.9233 17.208-19.989 19.7-33.313 2.0478-15.859-0.8387-27.309-8.6075-39.221 15.97 26.79-16.587 67.358-58.062 72.345z' fill='#4599d4'/><path class='st27' d='m1045.3 1149.2c-18.997-20.032-18.964-47.757-7.1843-65.92 5.8835-9.0721 13.291-16.028 23.366-20.561 20.051-9.0218 44.13-5.6545 60.873 11.406-4.72-4.8466-12.513-6.7474-22.606-4.6498 10.094-2.0976 17.886-0.1968 22.606 4.6498 4.5561 4.9653 5.1762 6.2052 6.5569 8.1873 7.5831 10.886 11.056 28.949 7.0943 42.437-3.8054 12.958-8.2128 20.094-18.735 29.073-18.792 16.036-52.432 15.983-71.971-4.6216z' fill='none'/><path class='st28' d='m1041.3 1078.8c13.148-15.744 29.723-22.726 49.881-20.392 1.5507 0.2102 3.0205 1.0632 4.3621 2.5764-4.2563-0.5851-10.145-0.2577-12.782 3.1526-16.704-2.5614-32.381 2.8754-41.462 14.663z' fill='#f47638'/><path class='st29' d='m1044.1 1147.9c-18.508-21.607-18.22-48.666-2.8207-69.14 9.0804-11.788 24.757-17.225 41.462-14.663-2.0305 2.6266-1.4376 6.4406 2.049 10.244-38.294 19.167-54.652 57.101-40.69 73.559z' fill='#4599d4'/><path class='st26' d='m1071.9 1164.1c-10.057-2.5925-18.246-6.4741-26.516-14.9l-1.2303-1.3061c-15.84-20.122 9.4946-58.498 40.69-73.559 0.1295 0.1414 0.2632 0.2827 0.4008 0.4241 3.5639 3.6595 8.9953 6.4225 14.403 7.6634-2.6311 34.879-18.8 83.704-27.747 81.678z' fill='#f5dc13'/><path class='st28' d='m1117.2 1153.9c-14.743 10.223-27.307 14.01-45.366 10.208 9.303 2.1066 25.227-48.275 27.747-81.678 3.4524 0.7921 6.895 0.9641 9.8358 0.3529 1.8443-0.3832 3.3206-1.0389 4.4135-1.8985 21.19 22.725 23.718 56.934 3.37 73.015z' fill='#f47638'/><path class='st29' d='m1128.3 1081.4c17.14 26.972 9.1903 56.496-11.093 72.534 20.934-16.544 17.21-50.945-3.37-73.015 2.8355-2.2305 3.0897-5.8338 0.4919-9.6121 3.4009 1.2882 9.7311 3.6862 13.971 10.093z' fill='#4599d4'/><ellipse class='st1' transform='matrix(.34241 -.93955 .93955 .34241 -284.5 1737.5)' cx='1099' cy='1072' rx='10.244' ry='18.207' fill='#f7f7fb'/></g></g></g></svg>";
-const POOL_PARTY_ACCOUNT: &str = "pool.pooltest.testnet";
 
 const NO_DEPOSIT: Balance = 0;
 const TGAS: Gas = 1_000_000_000_000;
-const TIME_THRESHOLD: u64 = 300_000_000_000; // 5 minutes
 
 #[near_bindgen]
 impl Contract {
 
+    /// Initializes the contract with the default $POOL metadata
     #[init]
-    pub fn new( ) -> Self {
-        assert!(!env::state_exists(), "Already initialized");
+    pub fn new_default_meta(
+        owner_id: ValidAccountId,
+        total_supply: U128,
+        pool_party_account: ValidAccountId,
+        cache_reserve: Option<bool>,
+        pricing: Option<PricingMode>,
+    ) -> Self {
+        Self::new(
+            owner_id,
+            total_supply,
+            FungibleTokenMetadata {
+                spec: FT_METADATA_SPEC.to_string(),
+                name: "Pool Party fungible token".to_string(),
+                symbol: "$POOL".to_string(),
+                icon: Some(DATA_IMAGE_SVG_NEAR_ICON.to_string()),
+                reference: None,
+                reference_hash: None,
+                decimals: 8,
+            },
+            pool_party_account,
+            cache_reserve,
+            pricing,
+        )
+    }
 
-        let owner_id: AccountId = String::from("gagdiez.testnet");
+    /// Initializes the contract minting `total_supply` tokens to `owner_id`,
+    /// and exchanging them against the reserve of `pool_party_account`.
+    /// With `cache_reserve`, the reserve of Pool Party is cached right away.
+    /// Tokens are sold at the `pricing` curve, or at the flat reserve price by default
+    #[init]
+    pub fn new(
+        owner_id: ValidAccountId,
+        total_supply: U128,
+        metadata: FungibleTokenMetadata,
+        pool_party_account: ValidAccountId,
+        cache_reserve: Option<bool>,
+        pricing: Option<PricingMode>,
+    ) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
 
-        let metadata = FungibleTokenMetadata {
-            spec: FT_METADATA_SPEC.to_string(),
-            name: "Pool Party fungible token".to_string(),
-            symbol: "$POOL".to_string(),
-            icon: Some(DATA_IMAGE_SVG_NEAR_ICON.to_string()),
-            reference: None,
-            reference_hash: None,
-            decimals: 8,
-        };
+        let total_supply: Balance = total_supply.into();
+        assert!(total_supply > 0, "The total supply must be positive");
 
         metadata.assert_valid();
+
+        let pricing = pricing.unwrap_or(PricingMode::Flat);
+        pricing.assert_valid(total_supply);
+
+        let pool_party_account: AccountId = pool_party_account.into();
+        assert!(pool_party_account != env::current_account_id(), "Pool Party cannot be this contract");
+
+        let owner_id: AccountId = owner_id.into();
+
         let mut this = Self {
+            owner_id: owner_id.clone(),
+            proposed_owner_id: None,
+            pool_party_account,
             pool_party_reserve: 0,
-            pool_party_next_raffle: 0,
+            pool_party_next_raffle: None,
+            pool_party_reserve_updated_at: 0,
+            paused: PauseStatus::default(),
+            config: Config::default(),
+            pending: PendingLedger::new(b"p".to_vec()),
+            claimable_refunds: LookupMap::new(b"r".to_vec()),
+            queue: ExchangeQueue::new(b"q".to_vec()),
+            redemptions: RedemptionLedger::new(b"w".to_vec(), b"u".to_vec()),
+            treasury: TreasuryStats::default(),
+            fees_accrued: 0,
+            pricing,
+            purchases: LookupMap::new(b"l".to_vec()),
+            period_sales: PeriodPurchases::default(),
+            allowlist: AllowlistSale::default(),
+            allowlist_used: LookupMap::new(b"g".to_vec()),
+            airdrops: AirdropDistributor::new(b"d".to_vec()),
+            vesting: VestingLedger::new(b"v".to_vec()),
             token: FungibleToken::new(b"a".to_vec()),
             metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
         };
@@ -67,172 +188,498 @@ impl Contract {
         // Register this contract as a user so it can receive and give tokens
         this.token.internal_register_account(&env::current_account_id()); 
 
-        // HARCODE total supply and give it to the owner_id
-        let total_supply: Balance = 10_000_000;
-        this.token.internal_register_account(&owner_id);
+        // Give the total supply to the owner_id
+        if owner_id != env::current_account_id() {
+            this.token.internal_register_account(&owner_id);
+        }
         this.token.internal_deposit(&owner_id, total_supply);
+        events::ft_mint(&owner_id, total_supply, Some("Initial supply"));
+
+        if cache_reserve.unwrap_or(false) {
+            this.cache_pool_party_reserve();
+        }
 
         this
     }
 
     fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
         log!("Closed @{} with {}", account_id, balance);
+        if balance > 0 {
+            events::ft_burn(&account_id, balance, Some("Account closed"));
+        }
     }
 
     fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance) {
         log!("Account @{} burned {}", account_id, amount);
+        events::ft_burn(&account_id, amount, None);
     }
 
     // We cache the reserve of Pool Party since it gets updated once per day at max
     pub fn cache_pool_party_reserve(&mut self) -> Promise {
-        poolparty_contract::get_pool_info(
-            &POOL_PARTY_ACCOUNT,
-            NO_DEPOSIT,
-            20*TGAS
-        ).then(this_contract::cache_pool_party_reserve_callback(
+        self.get_pool_info().then(this_contract::cache_pool_party_reserve_callback(
             &env::current_account_id(),
             NO_DEPOSIT,
-            5*TGAS,
+            self.config.cache_callback_gas.0,
         ))
     }
 
     #[private]
     pub fn cache_pool_party_reserve_callback(&mut self) -> bool {
-        if !external::did_promise_succeded() {
-            log!("No result found on callback");
-            return false;
+        self.update_cache_from_promise()
+    }
+
+    // Assert the user-given deadline (in nanoseconds) has not passed
+    fn panic_if_past_deadline(&self, deadline: Option<U64>) {
+        if let Some(deadline) = deadline {
+            assert!(env::block_timestamp() <= u64::from(deadline), "The deadline for this exchange has passed");
         }
+    }
+
+    // Assert the cache was initialized, there is enough gas, the exchange is not paused,
+    // and the deadline has not passed
+    fn panic_if_cannot_exchange(&self, direction: ExchangeDirection, deadline: Option<U64>) {
+        assert!(self.pool_party_next_raffle.is_some(), "The reserve cache is not initialized, call cache_pool_party_reserve first");
 
-        // Get response, return false if failed
-        let pool_info: PoolInfo = match env::promise_result(0) {
-            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<PoolInfo>(&value).unwrap(),
-            _ => { log!("Getting info from Pool Party failed"); return false; },
+        let mut required_gas = match direction {
+            ExchangeDirection::NearForTokens => self.config.near_for_tokens_prepaid_gas.0,
+            ExchangeDirection::TokensForTickets => self.config.tokens_for_tickets_prepaid_gas.0,
         };
+        if self.is_cache_stale() {
+            required_gas += self.config.get_pool_info_gas.0 + self.config.refresh_callback_gas.0;
+        }
+        assert!(env::prepaid_gas() >= required_gas, "This method requires at least {} TGAS to run", required_gas / TGAS);
+        self.panic_if_paused(direction);
+        self.panic_if_past_deadline(deadline);
+    }
 
-        let next_raffle = u64::from(pool_info.next_prize_tmstmp);
+    // Exchange $POOL tokens for tickets in the reserve of Pool Party
+    // The call fails if it would give less than `min_tickets_out`, or if it runs after `deadline`
+    // Right before the raffle, and until the cache is updated, the exchange is queued instead
+    pub fn exchange_tokens_for_tickets(
+        &mut self,
+        amount_tokens: U128,
+        min_tickets_out: Option<U128>,
+        deadline: Option<U64>,
+    ) -> PromiseOrValue<bool> {
+        self.panic_if_cannot_exchange(ExchangeDirection::TokensForTickets, deadline);
 
-        if next_raffle <= self.pool_party_next_raffle {
-            log!("No need to update");
-            return true
+        // Transfer the tokens from the user to this contract
+        let amount_tokens = u128::from(amount_tokens);
+        let user: AccountId = env::predecessor_account_id();
+        let this: AccountId = env::current_account_id();
+        self.internal_transfer(&user, &this, amount_tokens, None);
+
+        self.exchange_or_wait(ExchangeDirection::TokensForTickets, user, amount_tokens, min_tickets_out, deadline)
+    }
+
+    // Start the exchange, unless the cache has to be updated first, or the raffle is close
+    fn exchange_or_wait(
+        &mut self,
+        direction: ExchangeDirection,
+        user: AccountId,
+        amount: Balance,
+        min_out: Option<U128>,
+        deadline: Option<U64>,
+    ) -> PromiseOrValue<bool> {
+        if self.is_cache_stale() {
+            return self.refresh_then_exchange(direction, &user, amount, min_out, deadline).into();
         }
 
-        self.pool_party_reserve = u128::from(pool_info.reserve);
-        self.pool_party_next_raffle = next_raffle;
+        if self.is_close_to_raffle() {
+            self.enqueue(direction, &user, amount, min_out, deadline);
+            return PromiseOrValue::Value(true);
+        }
 
-        log!("Reserve: {}. Next update: {}", self.pool_party_reserve, next_raffle);
-        true
+        self.try_start_exchange(direction, user, amount, min_out, deadline, false)
+            .unwrap_or_else(|error| panic!("{}", error))
+            .into()
     }
 
-    // Assert we are at least T min. away from the raffle, and that we didn't used the reserve
-    fn panic_if_close_to_raffle(&mut self) {
-        assert!(env::block_timestamp() < self.pool_party_next_raffle - TIME_THRESHOLD, 
-                "Cannot exchange right before the raffle. Wait for the raffle, or update the cache");
+    // Start the exchange of `amount`, already held by the contract, at the current price
+    fn try_start_exchange(
+        &mut self,
+        direction: ExchangeDirection,
+        user: AccountId,
+        amount: Balance,
+        min_out: Option<U128>,
+        deadline: Option<U64>,
+        via_transfer_call: bool,
+    ) -> Result<Promise, String> {
+        if deadline.map_or(false, |deadline| env::block_timestamp() > deadline.0) {
+            return Err("The deadline for this exchange has passed".to_string());
+        }
+
+        match direction {
+            ExchangeDirection::NearForTokens => {
+                // Only the NEAR left after the sale fee is staked and buys tokens
+                let fee = self.sale_fee(amount);
+                let tokens = self.tokens_for_exchange(amount - fee, min_out)?;
+                self.record_purchase(&user, tokens)?;
+                Ok(self.start_near_for_tokens(user, amount - fee, fee, tokens))
+            }
+            ExchangeDirection::TokensForTickets => {
+                let tickets = self.tickets_for_exchange(amount, min_out)?;
+                Ok(self.start_tokens_for_tickets(user, amount, tickets, via_transfer_call))
+            }
+        }
     }
 
-    // Exchange $POOL tokens for tickets in the reserve of Pool Party
-    pub fn exchange_tokens_for_tickets(&mut self, amount_tokens: U128) -> Promise {
-        assert!(env::prepaid_gas() >= 120 * TGAS, "This method requires at least 120 TGAS to run");
+    // Compute how many tickets correspond to `amount_tokens`, which the contract already
+    // holds but are still part of the circulating supply
+    fn tickets_for_exchange(&self, amount_tokens: Balance, min_tickets_out: Option<U128>) -> Result<Balance, String> {
+        let amount_tickets = self.tickets_for_tokens(amount_tokens, self.circulating_supply() + amount_tokens);
 
-        // Assert we are at least T min. away from the raffle, to ensure the cached reserve is valid
-        self.panic_if_close_to_raffle();
+        if let Some(min_tickets_out) = min_tickets_out {
+            if amount_tickets < min_tickets_out.0 {
+                return Err(format!("Slippage: would get {} tickets, less than the minimum {}",
+                                   amount_tickets, min_tickets_out.0));
+            }
+        }
+        Ok(amount_tickets)
+    }
 
-        // compute how many tickets correspond to the user
-        let amount_tokens_u128 = u128::from(amount_tokens);
-        let tokens_own_by_contract = self.token.internal_unwrap_balance_of(&env::current_account_id());
-        let amount_tickets: U256 = U256::from(self.pool_party_reserve * amount_tokens_u128) / U256::from(self.token.total_supply - tokens_own_by_contract);
-        let amount_tickets_u128 = amount_tickets.as_u128();
+    // Remove the tickets from the cached reserve, and ask Pool Party to give them to the user
+    fn start_tokens_for_tickets(&mut self, user: AccountId, tokens: Balance, tickets: Balance, via_transfer_call: bool) -> Promise {
+        self.pool_party_reserve -= tickets;
 
-        // Remove them from the cached reserve
-        self.pool_party_reserve -= amount_tickets_u128;
+        let operation_id =
+            self.pending.start(ExchangeDirection::TokensForTickets, &user, tokens, tickets, 0, via_transfer_call);
 
-        // Transfer the tokens from the user to this contract
-        let user: AccountId = env::predecessor_account_id();
-        let this: AccountId = env::current_account_id();
-        self.token.internal_transfer(&user, &this, amount_tokens_u128, None);
+        // Tokens sent through `ft_transfer_call` are returned by `ft_resolve_transfer`
+        let callback = if via_transfer_call {
+            this_contract::redeem_tickets_callback(
+                operation_id, user.clone(), tokens, tickets,
+                &env::current_account_id(), NO_DEPOSIT, self.config.tickets_callback_gas.0,
+            )
+        } else {
+            this_contract::exchange_tokens_for_tickets_callback(
+                operation_id, user.clone(), tokens, tickets,
+                &env::current_account_id(), NO_DEPOSIT, self.config.tickets_callback_gas.0,
+            )
+        };
 
-        // ask to transfer tickets to the user
         poolparty_contract::give_from_reserve(
-            user.clone(),
-            U128::from(amount_tickets_u128),
-            &POOL_PARTY_ACCOUNT,
+            user,
+            U128::from(tickets),
+            &self.pool_party_account,
             NO_DEPOSIT,
-            120*TGAS
-        ).then(this_contract::exchange_tokens_for_tickets_callback(
-            user.clone(),
-            amount_tokens_u128,
-            amount_tickets_u128,
-            &env::current_account_id(),
-            NO_DEPOSIT,
-            50*TGAS,
-        ))
+            self.config.give_from_reserve_gas.0
+        ).then(callback)
     }
 
     
     #[private] // checks that caller_id == this contract
-    pub fn exchange_tokens_for_tickets_callback(&mut self, user: AccountId, tokens: Balance, tickets:Balance) -> bool {
-        if !external::did_promise_succeded(){
-            log!("Failed, returning tokens to {}", &user);
+    pub fn exchange_tokens_for_tickets_callback(&mut self, operation_id: u64, user: AccountId, tokens: Balance, tickets:Balance) -> bool {
+        // The owner may have reconciled the operation while the callback was delayed
+        if self.pending.finish(operation_id).is_none() {
+            log!("Operation {} was already reconciled, ignoring its callback", operation_id);
+            return false
+        }
+
+        if let Err(error) = external::promise_result(0) {
+            log!("Giving the tickets failed: {}. Returning tokens to {}", error, &user);
             let this: AccountId = env::current_account_id();
-            self.token.internal_transfer(&this, &user, tokens, None);
+            self.internal_transfer(&this, &user, tokens, None);
             self.pool_party_reserve += tickets;
+            self.treasury.record_tokens_refund(tokens);
+            events::exchange_tokens_for_tickets_refund(&user, tokens, tickets);
             return false
         }
 
+        self.treasury.record_tickets(tokens, tickets);
+        events::exchange_tokens_for_tickets(&user, tokens, tickets);
         true
     }
 
+    // Exchange the attached NEAR for $POOL tokens, staking the NEAR in Pool Party
+    // The call fails if it would give less than `min_tokens_out`, or if it runs after `deadline`
+    // Right before the raffle, and until the cache is updated, the exchange is queued instead
+    // During the allowlist phase, the buyer proves its `allowlist` allocation
     #[payable]
-    pub fn exchange_near_for_tokens(&mut self) -> Promise {
-        assert!(env::prepaid_gas() >= 30 * TGAS, "This method requires at least 30 TGAS to run");
-
-        // Assert we are at least T min. away from the raffle, to ensure the cached reserve is valid
-        self.panic_if_close_to_raffle();
+    pub fn exchange_near_for_tokens(
+        &mut self,
+        min_tokens_out: Option<U128>,
+        deadline: Option<U64>,
+        allowlist: Option<AllowlistProof>,
+    ) -> PromiseOrValue<bool> {
+        self.panic_if_cannot_exchange(ExchangeDirection::NearForTokens, deadline);
 
+        let user: AccountId = env::predecessor_account_id();
         let near_amount = env::attached_deposit();
+        assert!(near_amount > 0, "Attach the NEAR to exchange for tokens");
+        self.use_allowlist(&user, near_amount, allowlist);
 
-        // price_per_token = self.pool_party_reserve / ( self.token.total_supply - amount_tokens_already_bought);
-        // token_amount = near_amount / price_per_token
-        let tokens_own_by_contract = self.token.internal_unwrap_balance_of(&env::current_account_id());
-        let tot_minus_ours = self.token.total_supply - tokens_own_by_contract;
-        let token_amount = (U256::from(near_amount * tot_minus_ours) / U256::from(self.pool_party_reserve)).as_u128();
+        self.exchange_or_wait(ExchangeDirection::NearForTokens, user, near_amount, min_tokens_out, deadline)
+    }
 
-        log!("Exchanging {} N for {} tokens", near_amount, token_amount);
+    fn tokens_for_exchange(&self, near_amount: Balance, min_tokens_out: Option<U128>) -> Result<Balance, String> {
+        let token_amount = self.tokens_for_near(near_amount)?;
 
         // Check if we have enought tokens to sell
-        assert!(token_amount <= tokens_own_by_contract, "We do not have enough tokens to sell");
+        if token_amount > self.tokens_for_sale() {
+            return Err("We do not have enough tokens to sell".to_string());
+        }
+
+        if let Some(min_tokens_out) = min_tokens_out {
+            if token_amount < min_tokens_out.0 {
+                return Err(format!("Slippage: would get {} tokens, less than the minimum {}",
+                                   token_amount, min_tokens_out.0));
+            }
+        }
+        Ok(token_amount)
+    }
+
+    // Deposit the NEAR in Pool Party, the tokens are given to the user on the callback.
+    // The fee is kept by the contract until then
+    fn start_near_for_tokens(&mut self, user: AccountId, near_amount: Balance, fee: Balance, token_amount: Balance) -> Promise {
+        let operation_id =
+            self.pending.start(ExchangeDirection::NearForTokens, &user, token_amount, near_amount, fee, false);
 
-        // deposit the money in pool party
         poolparty_contract::deposit_and_stake(
-            &POOL_PARTY_ACCOUNT,
+            &self.pool_party_account,
             near_amount,
-            190*TGAS
+            self.config.deposit_and_stake_gas.0
         ).then(this_contract::exchange_near_for_tokens_callback(
-            env::predecessor_account_id(),
+            operation_id,
+            user,
             token_amount,
             near_amount,
+            fee,
             &env::current_account_id(),
             NO_DEPOSIT,
-            20*TGAS,
+            self.config.near_callback_gas.0,
         ))
     }
 
     #[private]
-    pub fn exchange_near_for_tokens_callback(&mut self, user: AccountId, tokens: Balance, tickets: Balance) -> bool {
-        if external::did_promise_succeded(){
-            // Succeeded in staking NEARs, transfer tokens to the user
-            let this = env::current_account_id();
-            self.token.internal_transfer(&this, &user, tokens, None);
-            self.pool_party_reserve += tickets;
+    pub fn exchange_near_for_tokens_callback(
+        &mut self,
+        operation_id: u64,
+        user: AccountId,
+        tokens: Balance,
+        tickets: Balance,
+        fee: Balance,
+    ) -> bool {
+        if self.pending.finish(operation_id).is_none() {
+            log!("Operation {} was already reconciled, ignoring its callback", operation_id);
+            return false
+        }
+
+        // Only the outcome matters, the NEAR was staked even if the returned value is unexpected
+        if let Err(error) = external::promise_result(0) {
+            // Failed to stake nears, send money back to the user, fee included
+            log!("Staking failed: {}. Returning NEAR to {}", error, &user);
+            self.release_purchase(&user, tokens);
+            self.release_allowlist(&user, tickets + fee);
+            self.treasury.record_near_refund(tickets + fee);
+            events::exchange_near_for_tokens_refund(&user, tickets + fee);
+            self.refund(user, tickets + fee);
+            return false
+        }
+
+        // Succeeded in staking NEARs, transfer tokens to the user
+        let this = env::current_account_id();
+        self.internal_transfer(&this, &user, tokens, None);
+        self.pool_party_reserve += tickets;
+        self.treasury.record_sale(tickets, tokens);
+        self.collect_fee(fee);
+        events::exchange_near_for_tokens(&user, tickets, tokens, fee, self.marginal_price());
+        true
+    }
+}
+
+impl Contract {
+    // Update the cache with the PoolInfo returned by Pool Party, return false if failed
+    fn update_cache_from_promise(&mut self) -> bool {
+        // Get response, return false if failed. A malformed response keeps the old cache
+        let pool_info: PoolInfo = match external::promise_result_json(0) {
+            Ok(pool_info) => pool_info,
+            Err(error) => {
+                log!("Getting info from Pool Party failed: {}", error);
+                return false;
+            }
+        };
+
+        let next_raffle = u64::from(pool_info.next_prize_tmstmp);
+        self.pool_party_reserve_updated_at = env::block_timestamp();
+
+        if self.pool_party_next_raffle.map_or(false, |cached| next_raffle <= cached) {
+            log!("No need to update");
             return true
         }
 
-        // Failed to stake nears, send money back to the user
-        Promise::new(user).transfer(tickets);
-        return false
+        // Exchanges in flight may not be reflected yet in Pool Party. The tickets being given
+        // and the NEAR being unstaked were taken from the cache when they started, so they are
+        // kept out of it until their callbacks confirm or revert them. NEAR being staked is
+        // only added to the cache by its callback, so it needs no adjustment
+        let pending = self.pending.tickets_out + self.redemptions.near_out;
+        self.pool_party_reserve = u128::from(pool_info.reserve).saturating_sub(pending);
+        self.pool_party_next_raffle = Some(next_raffle);
+
+        events::reserve_cache_update(self.pool_party_reserve, next_raffle);
+        true
+    }
+
+    // Transfers tokens between accounts, logging the NEP-141 event
+    fn internal_transfer(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: Balance, memo: Option<String>) {
+        self.token.internal_transfer(sender_id, receiver_id, amount, memo.clone());
+        events::ft_transfer(sender_id, receiver_id, amount, memo.as_deref());
+    }
+
+    fn tokens_own_by_contract(&self) -> Balance {
+        self.token.internal_unwrap_balance_of(&env::current_account_id())
+    }
+
+    // Tokens of the contract that are neither promised to an exchange in flight,
+    // being exchanged for tickets or NEAR, nor escrowed in the queue, the airdrop or vesting.
+    // Until a failed `ft_transfer_call` exchange is reconciled, its tokens are counted
+    // as being exchanged but were already given back, so they can exceed the balance
+    fn tokens_for_sale(&self) -> Balance {
+        let reserved = self.pending.tokens_out
+            + self.pending.tokens_in
+            + self.redemptions.tokens_in
+            + self.queue.tokens_escrowed
+            + self.airdrops.tokens_escrowed()
+            + self.vesting.tokens_escrowed;
+        self.tokens_own_by_contract().saturating_sub(reserved)
+    }
+
+    // Tokens in hands of the users, the ones backed by the reserve of Pool Party.
+    // Tokens escrowed in the queue, the airdrop and vesting still belong to the users
+    fn circulating_supply(&self) -> Balance {
+        self.token.total_supply - self.tokens_own_by_contract()
+            + self.queue.tokens_escrowed
+            + self.airdrops.tokens_escrowed()
+            + self.vesting.tokens_escrowed
+    }
+
+    // Exchanges close T min. before the raffle, since the raffle changes the reserve
+    fn exchange_closes_at(&self) -> Option<u64> {
+        self.pool_party_next_raffle.map(|next_raffle| next_raffle.saturating_sub(self.config.time_threshold.0))
+    }
+
+    fn is_close_to_raffle(&self) -> bool {
+        self.exchange_closes_at().map_or(true, |closes_at| env::block_timestamp() >= closes_at)
+    }
+
+    // The raffle took place, so the cached reserve is outdated
+    fn is_cache_stale(&self) -> bool {
+        self.pool_party_next_raffle.map_or(true, |next_raffle| env::block_timestamp() >= next_raffle)
+    }
+
+    // Gas attached to the calls of an exchange
+    fn exchange_gas(&self, direction: ExchangeDirection) -> Gas {
+        match direction {
+            ExchangeDirection::NearForTokens => self.config.deposit_and_stake_gas.0 + self.config.near_callback_gas.0,
+            ExchangeDirection::TokensForTickets => {
+                self.config.give_from_reserve_gas.0 + self.config.tickets_callback_gas.0
+            }
+        }
+    }
+
+    // Give back the NEAR or tokens held for an exchange that will not take place
+    fn return_escrow(&mut self, direction: ExchangeDirection, user: &AccountId, amount: Balance) {
+        match direction {
+            ExchangeDirection::NearForTokens => {
+                self.release_allowlist(user, amount);
+                self.treasury.record_near_refund(amount);
+                self.refund(user.clone(), amount);
+            }
+            ExchangeDirection::TokensForTickets => {
+                self.treasury.record_tokens_refund(amount);
+                let this: AccountId = env::current_account_id();
+                self.internal_transfer(&this, user, amount, None);
+            }
+        }
+    }
+
+    // tickets = reserve * amount_tokens / circulating_supply
+    fn tickets_for_tokens(&self, amount_tokens: Balance, circulating_supply: Balance) -> Balance {
+        assert!(circulating_supply > 0, "No tokens in circulation, cannot compute the price");
+
+        let amount_tickets: U256 =
+            U256::from(self.pool_party_reserve) * U256::from(amount_tokens) / U256::from(circulating_supply);
+        u256_to_balance(amount_tickets)
+    }
+
+    // price_per_token = reserve / circulating_supply
+    // token_amount = near_amount / price_per_token
+    // With a bonding curve, the price depends instead on the tokens already sold.
+    // Fails if there is no price, or if `near_amount` does not buy a single token unit
+    fn tokens_for_near(&self, near_amount: Balance) -> Result<Balance, String> {
+        let token_amount = if self.pricing != PricingMode::Flat {
+            self.pricing.tokens_for_near(self.tokens_sold(), near_amount, self.token.total_supply)
+        } else {
+            if self.pool_party_reserve == 0 {
+                return Err("The cached reserve is empty, cannot compute the price".to_string());
+            }
+            let circulating_supply = self.circulating_supply();
+            if circulating_supply == 0 {
+                return Err("No tokens in circulation, cannot compute the price".to_string());
+            }
+
+            let token_amount: U256 =
+                U256::from(near_amount) * U256::from(circulating_supply) / U256::from(self.pool_party_reserve);
+            u256_to_balance(token_amount)
+        };
+
+        if token_amount == 0 {
+            return Err(format!("{} yoctoNEAR is not enough to buy a token", near_amount));
+        }
+        Ok(token_amount)
+    }
+}
+
+// Same as `impl_fungible_token_core!`, but logging NEP-141 events
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>) {
+        let sender_id = env::predecessor_account_id();
+        self.token.ft_transfer(receiver_id.clone(), amount, memo.clone());
+        events::ft_transfer(&sender_id, receiver_id.as_ref(), amount.into(), memo.as_deref());
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let sender_id = env::predecessor_account_id();
+        let promise = self.token.ft_transfer_call(receiver_id.clone(), amount, memo.clone(), msg);
+        events::ft_transfer(&sender_id, receiver_id.as_ref(), amount.into(), memo.as_deref());
+        promise
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: ValidAccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    #[private]
+    fn ft_resolve_transfer(&mut self, sender_id: ValidAccountId, receiver_id: ValidAccountId, amount: U128) -> U128 {
+        let sender_id: AccountId = sender_id.into();
+        let (used_amount, burned_amount) =
+            self.token.internal_ft_resolve_transfer(&sender_id, receiver_id.clone(), amount);
+
+        if burned_amount > 0 {
+            self.on_tokens_burned(sender_id, burned_amount);
+        } else if used_amount < amount.0 {
+            events::ft_transfer(receiver_id.as_ref(), &sender_id, amount.0 - used_amount, Some("refund"));
+        }
+        used_amount.into()
     }
 }
 
-near_contract_standards::impl_fungible_token_core!(Contract, token, on_tokens_burned);
 near_contract_standards::impl_fungible_token_storage!(Contract, token, on_account_closed);
 
 #[near_bindgen]
@@ -244,9 +691,10 @@ impl FungibleTokenMetadataProvider for Contract {
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
     use near_sdk::test_utils::{accounts, VMContextBuilder};
     use near_sdk::MockedBlockchain;
-    use near_sdk::{testing_env, Balance};
+    use near_sdk::{testing_env, Balance, PromiseResult, RuntimeFeesConfig, VMConfig};
 
     use super::*;
 
@@ -261,16 +709,1430 @@ mod tests {
         builder
     }
 
+    fn new_contract(owner_id: ValidAccountId) -> Contract {
+        Contract::new_default_meta(owner_id, TOTAL_SUPPLY.into(), accounts(5), None, None)
+    }
+
     #[test]
     fn test_new() {
         let mut context = get_context(accounts(1));
         testing_env!(context.build());
-        let contract = Contract::new();
+        let contract = new_contract(accounts(1));
         testing_env!(context.is_view(true).build());
         assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY);
         assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
     }
 
+    #[test]
+    fn test_new_with_metadata() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "Test".to_string(),
+            symbol: "TST".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        };
+        let contract = Contract::new(accounts(3), U128(42), metadata, accounts(5), None, None);
+        assert_eq!(contract.ft_total_supply().0, 42);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 42);
+        assert_eq!(contract.ft_metadata().symbol, "TST");
+        assert_eq!(&contract.pool_party_account, accounts(5).as_ref());
+        assert_eq!(&contract.owner_id, accounts(3).as_ref());
+    }
+
+    #[test]
+    #[should_panic(expected = "The total supply must be positive")]
+    fn test_new_zero_supply() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        Contract::new_default_meta(accounts(1), U128(0), accounts(5), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool Party cannot be this contract")]
+    fn test_new_pool_party_is_self() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        Contract::new_default_meta(accounts(1), TOTAL_SUPPLY.into(), accounts(0), None, None);
+    }
+
+    #[test]
+    fn test_transfer_ownership() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        assert_eq!(&contract.get_owner(), accounts(1).as_ref());
+
+        testing_env!(context.attached_deposit(1).build());
+        contract.propose_owner(accounts(2));
+        assert_eq!(&contract.get_owner(), accounts(1).as_ref());
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.accept_owner();
+        assert_eq!(&contract.get_owner(), accounts(2).as_ref());
+        assert_eq!(contract.get_proposed_owner(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_propose_owner_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(1).build());
+        contract.propose_owner(accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_propose_owner_without_yocto() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        contract.propose_owner(accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the proposed owner can accept")]
+    fn test_accept_owner_not_proposed() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = new_contract(accounts(1));
+        contract.propose_owner(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_owner();
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        assert_eq!(contract.is_paused(), PauseStatus::default());
+
+        contract.pause(Some(ExchangeDirection::NearForTokens));
+        assert!(contract.is_paused().near_for_tokens);
+        assert!(!contract.is_paused().tokens_for_tickets);
+        let paused = events_logged().into_iter().find(|event| event["event"] == "paused").unwrap();
+        assert_eq!(paused["data"][0]["direction"], "near_for_tokens");
+        assert_eq!(paused["data"][0]["status"]["near_for_tokens"], true);
+
+        contract.pause(None);
+        assert!(contract.is_paused().tokens_for_tickets);
+
+        contract.unpause(None);
+        assert_eq!(contract.is_paused(), PauseStatus::default());
+        let unpaused = events_logged().into_iter().find(|event| event["event"] == "unpaused").unwrap();
+        assert!(unpaused["data"][0]["direction"].is_null());
+    }
+
+    #[test]
+    #[should_panic(expected = "The exchange is paused, try again later")]
+    fn test_exchange_when_paused() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+        contract.pause(Some(ExchangeDirection::TokensForTickets));
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(10), None, None);
+    }
+
+    fn events_logged() -> Vec<near_sdk::serde_json::Value> {
+        near_sdk::test_utils::get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| near_sdk::serde_json::from_str(event).unwrap())
+            .collect()
+    }
+
+    // Simulates a cached reserve for the next raffle, one hour from now
+    fn set_reserve(contract: &mut Contract, reserve: Balance) {
+        contract.pool_party_reserve = reserve;
+        contract.pool_party_next_raffle = Some(env::block_timestamp() + 3_600_000_000_000);
+    }
+
+    // A sale of accounts(1) with a cached reserve of 10_000: the contract sells `inventory`
+    // tokens at `pricing`, and `configure` changes the default config
+    fn new_sale_contract(
+        context: &VMContextBuilder,
+        inventory: Balance,
+        pricing: Option<PricingMode>,
+        configure: impl FnOnce(&mut Config),
+    ) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1), TOTAL_SUPPLY.into(), accounts(5), None, pricing);
+        set_reserve(&mut contract, 10_000);
+        if inventory > 0 {
+            contract.internal_transfer(&accounts(1).into(), &accounts(0).into(), inventory, None);
+        }
+
+        let mut config = contract.get_config();
+        configure(&mut config);
+        contract.update_config(config);
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "Slippage: would get 100 tickets, less than the minimum 101")]
+    fn test_exchange_tokens_for_tickets_slippage() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(TOTAL_SUPPLY / 100), Some(U128(101)), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The deadline for this exchange has passed")]
+    fn test_exchange_near_for_tokens_deadline() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(1_000).build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(10).build());
+        contract.exchange_near_for_tokens(None, Some(U64(999)), None);
+    }
+
+    #[test]
+    fn test_quotes() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+
+        // Nothing can be exchanged before caching the reserve
+        let quote = contract.quote_tokens_for_tickets(U128(100));
+        assert!(quote.close_to_raffle);
+
+        set_reserve(&mut contract, 10_000);
+        let quote = contract.quote_tokens_for_tickets(U128(TOTAL_SUPPLY / 100));
+        assert_eq!(quote.amount_out.0, 100);
+        assert!(!quote.close_to_raffle);
+        assert!(!quote.insufficient_inventory);
+
+        // The contract has no tokens to sell yet
+        let quote = contract.quote_near_for_tokens(U128(100));
+        assert_eq!(quote.amount_out.0, TOTAL_SUPPLY / 100);
+        assert!(quote.insufficient_inventory);
+    }
+
+    #[test]
+    fn test_get_exchange_state() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        let state = contract.get_exchange_state();
+        assert_eq!(state.reserve.0, 10_000);
+        assert_eq!(state.next_raffle, Some(U64(3_600_000_000_000)));
+        assert_eq!(state.exchange_closes_at.unwrap().0, 3_600_000_000_000 - contract.get_config().time_threshold.0);
+        assert_eq!(state.contract_tokens.0, 0);
+        assert_eq!(state.circulating_supply.0, TOTAL_SUPPLY);
+    }
+
+    #[test]
+    fn test_pricing_does_not_overflow() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let total_supply: Balance = 1_000_000_000_000_000;
+        let mut contract = Contract::new_default_meta(accounts(1), total_supply.into(), accounts(5), None, None);
+
+        // 1M NEAR in the reserve, 10^30 * 10^10 does not fit in a u128
+        set_reserve(&mut contract, 1_000_000 * 10u128.pow(24));
+        let quote = contract.quote_tokens_for_tickets(U128(10_000_000_000));
+        assert_eq!(quote.amount_out.0, 10u128.pow(25));
+
+        let quote = contract.quote_near_for_tokens(U128(10u128.pow(25)));
+        assert_eq!(quote.amount_out.0, 10_000_000_000);
+    }
+
+    #[test]
+    fn test_pricing_empty_reserve() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+
+        let quote = contract.quote_near_for_tokens(U128(100));
+        assert_eq!(quote.amount_out.0, 0);
+        assert!(quote.close_to_raffle);
+        assert!(quote.pricing_error.is_some());
+
+        // Once cached, an empty reserve still has no price
+        set_reserve(&mut contract, 0);
+        let quote = contract.quote_near_for_tokens(U128(100));
+        assert!(!quote.close_to_raffle);
+        assert_eq!(quote.pricing_error.as_deref(), Some("The cached reserve is empty, cannot compute the price"));
+    }
+
+    #[test]
+    fn test_quote_below_token_price() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10 * TOTAL_SUPPLY);
+
+        let quote = contract.quote_near_for_tokens(U128(1));
+        assert_eq!(quote.amount_out.0, 0);
+        assert_eq!(quote.pricing_error.as_deref(), Some("1 yoctoNEAR is not enough to buy a token"));
+    }
+
+    #[test]
+    #[should_panic(expected = "No tokens in circulation, cannot compute the price")]
+    fn test_pricing_no_circulating_supply() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = new_contract(accounts(0));
+        contract.quote_tokens_for_tickets(U128(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "No tokens in circulation, cannot compute the price")]
+    fn test_sale_no_circulating_supply() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(0));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(100).build());
+        contract.exchange_near_for_tokens(None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 yoctoNEAR is not enough to buy a token")]
+    fn test_sale_below_token_price() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        contract.internal_transfer(&accounts(1).into(), &accounts(0).into(), TOTAL_SUPPLY / 10, None);
+
+        // Each token costs more than a yoctoNEAR
+        set_reserve(&mut contract, 10 * TOTAL_SUPPLY);
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(1).build());
+        contract.exchange_near_for_tokens(None, None, None);
+    }
+
+    #[test]
+    fn test_update_config() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+
+        let mut config = contract.get_config();
+        config.give_from_reserve_gas = (100 * TGAS).into();
+        config.time_threshold = 600_000_000_000.into();
+        contract.update_config(config.clone());
+        assert_eq!(contract.get_config(), config);
+
+        set_reserve(&mut contract, 10_000);
+        assert_eq!(contract.get_exchange_state().exchange_closes_at.unwrap().0, 3_000_000_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "The gas attached when exchanging tokens for tickets exceeds the required prepaid gas")]
+    fn test_update_config_gas_above_prepaid() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+
+        let mut config = contract.get_config();
+        config.tickets_callback_gas = (100 * TGAS).into();
+        contract.update_config(config);
+    }
+
+    #[test]
+    fn test_events() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(2));
+        let mint = events_logged().into_iter().find(|event| event["event"] == "ft_mint").unwrap();
+        assert_eq!(mint["standard"], "nep141");
+        assert_eq!(mint["data"][0]["owner_id"], "charlie");
+        assert_eq!(mint["data"][0]["amount"], "10000000");
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.ft_transfer(accounts(1), U128(100), Some("hi".to_string()));
+        let transfer = events_logged().into_iter().find(|event| event["event"] == "ft_transfer").unwrap();
+        assert_eq!(transfer["data"][0]["old_owner_id"], "charlie");
+        assert_eq!(transfer["data"][0]["new_owner_id"], "bob");
+        assert_eq!(transfer["data"][0]["amount"], "100");
+        assert_eq!(transfer["data"][0]["memo"], "hi");
+    }
+
+    #[test]
+    fn test_redeem_tickets_with_transfer_call() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        // ft_transfer_call already moved the tokens to the contract
+        let amount = TOTAL_SUPPLY / 100;
+        contract.token.internal_transfer(&accounts(1).into(), &accounts(0).into(), amount, None);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).prepaid_gas(270 * TGAS).build());
+        let msg = r#"{"action": "redeem_tickets", "min_tickets": "100"}"#.to_string();
+        contract.ft_on_transfer(accounts(1), amount.into(), msg);
+        assert_eq!(contract.pool_party_reserve, 9_900);
+    }
+
+    #[test]
+    fn test_reconcile_transfer_call() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        let amount = TOTAL_SUPPLY / 100;
+        contract.token.internal_transfer(&accounts(1).into(), &accounts(0).into(), amount, None);
+        testing_env!(context.predecessor_account_id(accounts(0)).prepaid_gas(270 * TGAS).build());
+        let msg = r#"{"action": "redeem_tickets"}"#.to_string();
+        contract.ft_on_transfer(accounts(1), amount.into(), msg);
+
+        // The callback runs out of gas, so ft_resolve_transfer returns all the tokens
+        with_promise_result(&context, PromiseResult::Failed);
+        contract.ft_resolve_transfer(accounts(1), accounts(0), amount.into());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
+        assert_eq!(contract.tokens_for_sale(), 0);
+
+        // The tickets were given, so the tokens are taken back from the user
+        testing_env!(context.predecessor_account_id(accounts(1)).block_timestamp(3_600_000_000_000).build());
+        contract.reconcile_pending(0, true);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - amount);
+        assert_eq!(contract.pool_party_reserve, 9_900);
+        assert_eq!(contract.tokens_for_sale(), amount);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only $POOL tokens can be transferred")]
+    fn test_ft_on_transfer_other_token() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+
+        testing_env!(context.predecessor_account_id(accounts(4)).prepaid_gas(270 * TGAS).build());
+        let msg = r#"{"action": "redeem_tickets"}"#.to_string();
+        contract.ft_on_transfer(accounts(1), U128(100), msg);
+    }
+
+    #[test]
+    fn test_pending_operations() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(TOTAL_SUPPLY / 100), None, None);
+
+        let pending = contract.get_pending_operations(None, None);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(&pending[0].user, accounts(1).as_ref());
+        assert_eq!(pending[0].tokens.0, TOTAL_SUPPLY / 100);
+        assert_eq!(pending[0].tickets.0, 100);
+        assert_eq!(pending[0].direction, ExchangeDirection::TokensForTickets);
+        assert_eq!(contract.pending.tickets_out, 100);
+
+        // The operation is cleared once the callback runs
+        contract.pending.finish(pending[0].id.0);
+        assert!(contract.get_pending_operations(None, None).is_empty());
+        assert_eq!(contract.pending.tickets_out, 0);
+    }
+
+    #[test]
+    fn test_cache_with_pending_operations() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+        contract.internal_transfer(&accounts(1).into(), &accounts(0).into(), TOTAL_SUPPLY / 10, None);
+
+        // Give 100 tickets, and stake 100 NEAR
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(TOTAL_SUPPLY / 100), None, None);
+        testing_env!(context.attached_deposit(100).build());
+        contract.exchange_near_for_tokens(None, None, None);
+
+        // The raffle took place, and Pool Party did not apply any of them yet
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(0).build());
+        with_promise_result(&context, pool_info(12_000, 7_200_000_000_000, false));
+        assert!(contract.cache_pool_party_reserve_callback());
+        assert_eq!(contract.pool_party_reserve, 11_900);
+        assert_eq!(contract.get_exchange_state().near_being_staked.0, 100);
+
+        // The stake is added once confirmed
+        let tokens = contract.get_pending_operations(None, None)[1].tokens.0;
+        with_promise_result(&context, PromiseResult::Successful(vec![]));
+        contract.exchange_near_for_tokens_callback(1, accounts(1).into(), tokens, 100, 0);
+        assert_eq!(contract.pool_party_reserve, 12_000);
+    }
+
+    #[test]
+    fn test_reconcile_pending() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(TOTAL_SUPPLY / 100), None, None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - TOTAL_SUPPLY / 100);
+
+        // The give failed but the callback never ran: the user gets the tokens back
+        testing_env!(context.block_timestamp(3_600_000_000_000).build());
+        contract.reconcile_pending(0, false);
+        assert!(contract.get_pending_operations(None, None).is_empty());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
+        assert_eq!(contract.pool_party_reserve, 10_000);
+    }
+
+    #[test]
+    fn test_callback_after_reconcile() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+        contract.internal_transfer(&accounts(1).into(), &accounts(0).into(), TOTAL_SUPPLY / 10, None);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(100).build());
+        contract.exchange_near_for_tokens(None, None, None);
+        let balance = contract.ft_balance_of(accounts(1)).0;
+        testing_env!(context.attached_deposit(0).block_timestamp(3_600_000_000_000).build());
+        contract.reconcile_pending(0, true);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, balance + 90_000);
+
+        // The delayed callback arrives, but the exchange was already settled
+        context.predecessor_account_id(accounts(0));
+        with_promise_result(&context, PromiseResult::Successful(vec![]));
+        assert!(!contract.exchange_near_for_tokens_callback(0, accounts(1).into(), 90_000, 100, 0));
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, balance + 90_000);
+        assert_eq!(contract.pool_party_reserve, 10_100);
+    }
+
+    #[test]
+    #[should_panic(expected = "The operation can only be reconciled an hour after it started")]
+    fn test_reconcile_pending_too_early() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        // The callback may still be on its way
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(TOTAL_SUPPLY / 100), None, None);
+        testing_env!(context.block_timestamp(3_599_999_999_999).build());
+        contract.reconcile_pending(0, true);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_reconcile_pending_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.reconcile_pending(0, true);
+    }
+
+    #[test]
+    fn test_claim_refund() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        contract.add_claimable_refund(&accounts(2).into(), 1_000);
+        assert_eq!(contract.get_claimable_refund(accounts(2)).0, 1_000);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_refund();
+        assert_eq!(contract.get_claimable_refund(accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_treasury_stats() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(TOTAL_SUPPLY / 100), None, None);
+        context.predecessor_account_id(accounts(0));
+        with_promise_result(&context, PromiseResult::Successful(vec![]));
+        contract.exchange_tokens_for_tickets_callback(0, accounts(1).into(), TOTAL_SUPPLY / 100, 100);
+
+        // The tokens are sold again, but staking the NEAR fails
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(100).build());
+        contract.exchange_near_for_tokens(None, None, None);
+        context.predecessor_account_id(accounts(0)).attached_deposit(0);
+        with_promise_result(&context, PromiseResult::Failed);
+        contract.exchange_near_for_tokens_callback(1, accounts(1).into(), TOTAL_SUPPLY / 100, 100, 0);
+
+        let stats = contract.get_treasury_stats();
+        assert_eq!(stats.tokens_redeemed.0, TOTAL_SUPPLY / 100);
+        assert_eq!(stats.tickets_given.0, 100);
+        assert_eq!(stats.near_staked.0, 0);
+        assert_eq!(stats.near_refunded.0, 100);
+    }
+
+    #[test]
+    fn test_sale_fee() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+        contract.internal_transfer(&accounts(1).into(), &accounts(0).into(), TOTAL_SUPPLY / 10, None);
+
+        let mut config = contract.get_config();
+        config.sale_fee_bps = 100;
+        contract.update_config(config);
+
+        let quote = contract.quote_near_for_tokens(U128(1_000));
+        assert_eq!(quote.fee.0, 10);
+        assert_eq!(quote.amount_out.0, 990 * 9_000_000 / 10_000);
+
+        // Only the NEAR after the fee is staked, the fee is kept once the exchange succeeds
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(1_000).build());
+        contract.exchange_near_for_tokens(Some(quote.amount_out), None, None);
+        let operations = contract.get_pending_operations(None, None);
+        assert_eq!((operations[0].tickets.0, operations[0].fee.0), (990, 10));
+
+        context.predecessor_account_id(accounts(0)).attached_deposit(0);
+        with_promise_result(&context, PromiseResult::Successful(vec![]));
+        contract.exchange_near_for_tokens_callback(0, accounts(1).into(), quote.amount_out.0, 990, 10);
+        assert_eq!(contract.get_accrued_fees().0, 10);
+        assert_eq!(contract.get_treasury_stats().fees_collected.0, 10);
+        assert_eq!(contract.get_exchange_state().reserve.0, 10_990);
+    }
+
+    #[test]
+    #[should_panic(expected = "The sale fee cannot be higher than 1000 basis points")]
+    fn test_sale_fee_too_high() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+
+        let mut config = contract.get_config();
+        config.sale_fee_bps = 1_001;
+        contract.update_config(config);
+    }
+
+    // Each account buys at most 150_000 tokens per raffle, at least 10_000 at a time.
+    // With 1/10 of the supply for sale, a yoctoNEAR buys 900 tokens
+    fn limit_purchases(config: &mut Config) {
+        config.max_tokens_per_account = Some(U128(150_000));
+        config.min_purchase = Some(U128(10_000));
+    }
+
+    #[test]
+    fn test_purchase_allowance() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, TOTAL_SUPPLY / 10, None, limit_purchases);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(100).build());
+        contract.exchange_near_for_tokens(None, None, None);
+        let allowance = contract.get_purchase_allowance(accounts(1));
+        assert_eq!(allowance.bought.0, 90_000);
+        assert_eq!(allowance.remaining, Some(U128(60_000)));
+
+        // The next raffle period starts from scratch
+        contract.pool_party_next_raffle = Some(7_200_000_000_000);
+        let allowance = contract.get_purchase_allowance(accounts(1));
+        assert_eq!(allowance.bought.0, 0);
+        assert_eq!(allowance.remaining, Some(U128(150_000)));
+    }
+
+    #[test]
+    #[should_panic(expected = "The purchase exceeds the limits of this raffle, 60000 tokens left")]
+    fn test_purchase_limit_per_account() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, TOTAL_SUPPLY / 10, None, limit_purchases);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(100).build());
+        contract.exchange_near_for_tokens(None, None, None);
+        contract.exchange_near_for_tokens(None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The purchase is below the minimum of 10000 tokens")]
+    fn test_purchase_below_minimum() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, TOTAL_SUPPLY / 10, None, limit_purchases);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(1).build());
+        contract.exchange_near_for_tokens(None, None, None);
+    }
+
+    fn allowlist_leaf(account_id: ValidAccountId, allocation: Balance) -> Vec<u8> {
+        env::sha256(format!("{}:{}", account_id.as_ref(), allocation).as_bytes())
+    }
+
+    // Allowlist of accounts(1) with 500 yoctoNEAR and accounts(2) with 300, until the timestamp 1000.
+    // Returns the proof of accounts(1)
+    fn set_test_allowlist(contract: &mut Contract) -> AllowlistProof {
+        let mut leaves = vec![allowlist_leaf(accounts(1), 500), allowlist_leaf(accounts(2), 300)];
+        let proof = AllowlistProof { allocation: U128(500), proof: vec![leaves[1].clone().into()] };
+        leaves.sort();
+        contract.set_allowlist(env::sha256(&leaves.concat()).into(), U64(1_000));
+        proof
+    }
+
+    #[test]
+    fn test_allowlist_sale() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, TOTAL_SUPPLY / 10, None, |_| ());
+        let proof = set_test_allowlist(&mut contract);
+        assert!(!contract.get_allowlist().is_public);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(200).build());
+        contract.exchange_near_for_tokens(None, None, Some(proof.clone()));
+        assert_eq!(contract.get_allowlist_used(accounts(1)).0, 200);
+
+        // A failed purchase gives back its allocation
+        context.predecessor_account_id(accounts(0)).attached_deposit(0);
+        with_promise_result(&context, PromiseResult::Failed);
+        contract.exchange_near_for_tokens_callback(0, accounts(1).into(), 180_000, 200, 0);
+        assert_eq!(contract.get_allowlist_used(accounts(1)).0, 0);
+
+        // Once the sale is public, anyone can buy without a proof
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(100).block_timestamp(1_000).build());
+        assert!(contract.get_allowlist().is_public);
+        contract.exchange_near_for_tokens(None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid allowlist proof")]
+    fn test_allowlist_invalid_proof() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, TOTAL_SUPPLY / 10, None, |_| ());
+        let proof = set_test_allowlist(&mut contract);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).prepaid_gas(300 * TGAS).attached_deposit(100).build());
+        contract.exchange_near_for_tokens(None, None, Some(proof));
+    }
+
+    #[test]
+    #[should_panic(expected = "The purchase exceeds the allowlist allocation, 300 yoctoNEAR left")]
+    fn test_allowlist_allocation_exceeded() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, TOTAL_SUPPLY / 10, None, |_| ());
+        let proof = set_test_allowlist(&mut contract);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(200).build());
+        contract.exchange_near_for_tokens(None, None, Some(proof.clone()));
+        testing_env!(context.attached_deposit(400).build());
+        contract.exchange_near_for_tokens(None, None, Some(proof));
+    }
+
+    fn airdrop_leaf(index: u32, account_id: ValidAccountId, amount: Balance) -> Vec<u8> {
+        env::sha256(format!("{}:{}:{}", index, account_id.as_ref(), amount).as_bytes())
+    }
+
+    // Airdrop of 1_000 tokens to accounts(2) and 2_000 to accounts(3), until the timestamp 1000.
+    // Returns both leaves
+    fn start_test_airdrop(context: &mut VMContextBuilder, contract: &mut Contract) -> (Vec<u8>, Vec<u8>) {
+        let (first, second) = (airdrop_leaf(0, accounts(2), 1_000), airdrop_leaf(1, accounts(3), 2_000));
+        let mut leaves = vec![first.clone(), second.clone()];
+        leaves.sort();
+        let root = env::sha256(&leaves.concat());
+        testing_env!(context.attached_deposit(1).build());
+        assert_eq!(contract.start_airdrop(root.into(), U128(3_000), U64(1_000)), 0);
+        context.attached_deposit(0);
+        (first, second)
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_start_airdrop_without_yocto() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        contract.start_airdrop(airdrop_leaf(0, accounts(2), 1_000).into(), U128(1_000), U64(1_000));
+    }
+
+    #[test]
+    fn test_claim_airdrop() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, 0, None, |_| ());
+        let (first, second) = start_test_airdrop(&mut context, &mut contract);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 3_000);
+        assert_eq!(contract.tokens_for_sale(), 0);
+
+        // The claim registers the account
+        let storage_cost: Balance = contract.storage_balance_bounds().min.into();
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(storage_cost).build());
+        contract.claim_airdrop(0, U128(1_000), vec![second.into()]);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 1_000);
+        assert!(contract.is_airdrop_claimed(0));
+        assert!(!contract.is_airdrop_claimed(1));
+
+        // The unclaimed tokens go back to the owner after the expiry
+        testing_env!(context.attached_deposit(0).block_timestamp(1_000).build());
+        assert_eq!(contract.close_airdrop().0, 2_000);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 1_000);
+        assert_eq!(contract.get_airdrop(), None);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_timestamp(0).build());
+        assert_eq!(contract.start_airdrop(first.into(), U128(1), U64(1_000)), 1);
+        assert!(!contract.is_airdrop_claimed(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "The airdrop was already claimed")]
+    fn test_claim_airdrop_twice() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, 0, None, |_| ());
+        let (_, second) = start_test_airdrop(&mut context, &mut contract);
+
+        let storage_cost: Balance = contract.storage_balance_bounds().min.into();
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(storage_cost).build());
+        contract.claim_airdrop(0, U128(1_000), vec![second.clone().into()]);
+        contract.claim_airdrop(0, U128(1_000), vec![second.into()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid airdrop proof")]
+    fn test_claim_airdrop_invalid_proof() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, 0, None, |_| ());
+        let (first, _) = start_test_airdrop(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.claim_airdrop(1, U128(3_000), vec![first.into()]);
+    }
+
+    // accounts(2) vests 1_000 tokens of accounts(1) from the timestamp 0 to 1000, with the cliff at 250
+    fn create_test_vesting(context: &mut VMContextBuilder, contract: &mut Contract, revocable: bool) {
+        contract.token.internal_register_account(accounts(2).as_ref());
+        testing_env!(context.attached_deposit(1).build());
+        contract.create_vesting(accounts(2), U128(1_000), U64(0), U64(250), U64(1_000), revocable);
+        context.attached_deposit(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_create_vesting_without_yocto() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        contract.token.internal_register_account(accounts(2).as_ref());
+        contract.create_vesting(accounts(2), U128(1_000), U64(0), U64(250), U64(1_000), false);
+    }
+
+    #[test]
+    fn test_claim_vested() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, 0, None, |_| ());
+        create_test_vesting(&mut context, &mut contract, false);
+        assert_eq!(contract.tokens_for_sale(), 0);
+
+        testing_env!(context.block_timestamp(100).build());
+        assert_eq!(contract.get_vesting(accounts(2)).unwrap().locked.0, 1_000);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).block_timestamp(400).build());
+        assert_eq!(contract.claim_vested().0, 400);
+        let vesting = contract.get_vesting(accounts(2)).unwrap();
+        assert_eq!((vesting.vested.0, vesting.claimed.0, vesting.locked.0), (400, 400, 600));
+
+        testing_env!(context.block_timestamp(2_000).build());
+        assert_eq!(contract.claim_vested().0, 600);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.get_vesting(accounts(2)), None);
+    }
+
+    #[test]
+    fn test_terminate_vesting() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, 0, None, |_| ());
+        create_test_vesting(&mut context, &mut contract, true);
+
+        testing_env!(context.block_timestamp(300).build());
+        assert_eq!(contract.terminate_vesting(accounts(2)).0, 700);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 300);
+
+        // The tokens vested before the termination can still be claimed
+        testing_env!(context.predecessor_account_id(accounts(2)).block_timestamp(2_000).build());
+        assert_eq!(contract.get_vesting(accounts(2)).unwrap().locked.0, 0);
+        assert_eq!(contract.claim_vested().0, 300);
+    }
+
+    #[test]
+    #[should_panic(expected = "The vesting schedule is not revocable")]
+    fn test_terminate_irrevocable_vesting() {
+        let mut context = get_context(accounts(1));
+        let mut contract = new_sale_contract(&context, 0, None, |_| ());
+        create_test_vesting(&mut context, &mut contract, false);
+        contract.terminate_vesting(accounts(2));
+    }
+
+    #[test]
+    fn test_linear_pricing() {
+        let context = get_context(accounts(1));
+        let pricing = PricingMode::Linear { base_price: U128(100), max_price: U128(300) };
+        let contract = new_sale_contract(&context, TOTAL_SUPPLY, Some(pricing), |_| ());
+
+        // 100 * 1_000_000 + (300 - 100) * 1_000_000^2 / (2 * TOTAL_SUPPLY)
+        let quote = contract.quote_near_for_tokens(U128(110_000_000));
+        assert_eq!(quote.amount_out.0, 1_000_000);
+        assert_eq!(quote.average_price.0, 110);
+        assert_eq!(quote.marginal_price.0, 120);
+        assert!(!quote.insufficient_inventory);
+    }
+
+    #[test]
+    fn test_exponential_pricing() {
+        let context = get_context(accounts(1));
+        let pricing = PricingMode::Exponential { base_price: U128(100), growth_bps: 10_000, step: U128(1_000_000) };
+        let contract = new_sale_contract(&context, TOTAL_SUPPLY, Some(pricing), |_| ());
+
+        // The price doubles every million tokens: 100 * 1_000_000 + 200 * 1_000_000
+        let quote = contract.quote_near_for_tokens(U128(300_000_000));
+        assert_eq!(quote.amount_out.0, 2_000_000);
+        assert_eq!(quote.average_price.0, 150);
+        assert_eq!(quote.marginal_price.0, 400);
+    }
+
+    #[test]
+    #[should_panic(expected = "The price grows too much to sell the total supply")]
+    fn test_exponential_pricing_overflow() {
+        let context = get_context(accounts(1));
+        let pricing = PricingMode::Exponential { base_price: U128(100), growth_bps: 10_000, step: U128(1) };
+        new_sale_contract(&context, TOTAL_SUPPLY, Some(pricing), |_| ());
+    }
+
+    #[test]
+    fn test_redeem_tokens_for_near() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.redeem_tokens_for_near(U128(TOTAL_SUPPLY / 100), Some(U128(100)), None);
+        assert_eq!(contract.get_exchange_state().reserve.0, 9_900);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - TOTAL_SUPPLY / 100);
+
+        // Pool Party unstaked the NEAR, and is asked when it is released
+        context.predecessor_account_id(accounts(0)).epoch_height(10);
+        with_promise_result(&context, PromiseResult::Successful(vec![]));
+        contract.redeem_tokens_for_near_callback(0);
+        assert_eq!(contract.get_pending_redemptions(None, None).len(), 1);
+
+        with_promise_result(&context, pool_account(100, 14, false));
+        assert!(contract.redeem_unstaked_callback(0));
+        assert!(contract.get_pending_redemptions(None, None).is_empty());
+
+        let withdrawals = contract.get_pending_withdrawals(accounts(1));
+        assert_eq!(withdrawals.len(), 1);
+        assert_eq!(withdrawals[0].near.0, 100);
+        assert_eq!(withdrawals[0].withdrawable_at_epoch.0, 14);
+    }
+
+    #[test]
+    fn test_redeem_without_pool_account() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.redeem_tokens_for_near(U128(TOTAL_SUPPLY / 100), Some(U128(100)), None);
+        context.predecessor_account_id(accounts(0)).epoch_height(10);
+        with_promise_result(&context, PromiseResult::Successful(vec![]));
+        contract.redeem_tokens_for_near_callback(0);
+
+        // Pool Party is checked again when withdrawing
+        with_promise_result(&context, PromiseResult::Failed);
+        assert!(contract.redeem_unstaked_callback(0));
+        assert_eq!(contract.get_pending_withdrawals(accounts(1))[0].withdrawable_at_epoch.0, 10);
+    }
+
+    fn pending_withdrawal(near: Balance, epoch: u64) -> PendingWithdrawal {
+        PendingWithdrawal { near: near.into(), tokens: U128(1_000), withdrawable_at_epoch: epoch.into() }
+    }
+
+    #[test]
+    #[should_panic(expected = "There is no redemption ready to withdraw")]
+    fn test_withdraw_redeemed_not_ready() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.epoch_height(10).build());
+        let mut contract = new_contract(accounts(1));
+        contract.redemptions.push(&accounts(2).into(), pending_withdrawal(100, 14));
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.withdraw_redeemed();
+    }
+
+    #[test]
+    fn test_withdraw_redeemed() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.epoch_height(14).build());
+        let mut contract = new_contract(accounts(1));
+        contract.redemptions.push(&accounts(2).into(), pending_withdrawal(100, 14));
+        contract.redemptions.push(&accounts(2).into(), pending_withdrawal(50, 18));
+
+        // Another withdrawal already took the NEAR out of Pool Party
+        contract.redemptions.near_withdrawn = 100;
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.withdraw_redeemed();
+        assert_eq!(contract.get_pending_withdrawals(accounts(2)), vec![pending_withdrawal(50, 18)]);
+        assert_eq!(contract.redemptions.near_withdrawn, 0);
+    }
+
+    // Result of `get_account` in Pool Party for this contract
+    fn pool_account(unstaked: Balance, available_when: u64, available: bool) -> PromiseResult {
+        let account = format!(
+            r#"{{"account_id": "alice", "staked_balance": "0", "unstaked_balance": "{}", "available_when": {}, "available": {}}}"#,
+            unstaked, available_when, available
+        );
+        PromiseResult::Successful(account.into_bytes())
+    }
+
+    // Simulates `withdraw_redeemed_callback` receiving the account and the pool info
+    fn with_withdraw_results(context: &VMContextBuilder, account: PromiseResult, info: PromiseResult) {
+        testing_env!(context.build(), VMConfig::default(), RuntimeFeesConfig::default(), Default::default(), vec![account, info]);
+    }
+
+    #[test]
+    fn test_withdraw_redeemed_from_pool_party() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.epoch_height(14).build());
+        let mut contract = new_contract(accounts(1));
+        contract.redemptions.push(&accounts(2).into(), pending_withdrawal(100, 14));
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.withdraw_redeemed();
+        assert!(contract.redemptions.withdrawing.is_some());
+        assert!(contract.get_pending_withdrawals(accounts(2)).is_empty());
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        with_withdraw_results(&context, pool_account(150, 14, true), pool_info(10_000, 0, true));
+        contract.withdraw_redeemed_callback(0);
+        assert!(contract.redemptions.withdrawing.is_some());
+
+        // Pool Party released the NEAR of other redemptions too, it is kept for them
+        with_promise_result(&context, PromiseResult::Successful(vec![]));
+        contract.withdraw_all_callback(0, U128(150));
+        assert!(contract.redemptions.withdrawing.is_none());
+        assert_eq!(contract.redemptions.near_withdrawn, 50);
+        assert!(contract.get_pending_withdrawals(accounts(2)).is_empty());
+    }
+
+    #[test]
+    fn test_withdraw_redeemed_not_released() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.epoch_height(14).build());
+        let mut contract = new_contract(accounts(1));
+        contract.redemptions.push(&accounts(2).into(), pending_withdrawal(100, 14));
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.withdraw_redeemed();
+
+        // The NEAR is still unbonding, the redemption is kept for later
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        with_withdraw_results(&context, pool_account(100, 18, false), pool_info(10_000, 0, true));
+        contract.withdraw_redeemed_callback(0);
+        assert!(contract.redemptions.withdrawing.is_none());
+        assert_eq!(contract.get_pending_withdrawals(accounts(2)), vec![pending_withdrawal(100, 14)]);
+    }
+
+    #[test]
+    fn test_withdraw_all_shortfall() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.epoch_height(14).build());
+        let mut contract = new_contract(accounts(1));
+        contract.redemptions.push(&accounts(2).into(), pending_withdrawal(100, 14));
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.withdraw_redeemed();
+
+        // Less NEAR than expected was released, it waits for the rest
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        with_promise_result(&context, PromiseResult::Successful(vec![]));
+        contract.withdraw_all_callback(0, U128(60));
+        assert!(contract.redemptions.withdrawing.is_none());
+        assert_eq!(contract.redemptions.near_withdrawn, 60);
+        assert_eq!(contract.get_pending_withdrawals(accounts(2)), vec![pending_withdrawal(100, 14)]);
+    }
+
+    #[test]
+    fn test_reconcile_withdrawal() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.epoch_height(14).build());
+        let mut contract = new_contract(accounts(1));
+        contract.redemptions.push(&accounts(2).into(), pending_withdrawal(100, 14));
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.withdraw_redeemed();
+
+        // The callback ran out of gas after Pool Party released the NEAR
+        testing_env!(context.predecessor_account_id(accounts(1)).block_timestamp(3_600_000_000_000).build());
+        contract.reconcile_withdrawal(U128(100));
+        assert!(contract.get_withdrawal_in_progress().is_none());
+        assert_eq!(contract.redemptions.near_withdrawn, 100);
+        assert_eq!(contract.get_pending_withdrawals(accounts(2)), vec![pending_withdrawal(100, 14)]);
+
+        // A late callback is ignored, and the user can withdraw again
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        with_promise_result(&context, PromiseResult::Successful(vec![]));
+        contract.withdraw_all_callback(0, U128(100));
+        assert_eq!(contract.redemptions.near_withdrawn, 100);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.withdraw_redeemed();
+        assert_eq!(contract.redemptions.near_withdrawn, 0);
+    }
+
+    #[test]
+    fn test_reconcile_redemption() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.redeem_tokens_for_near(U128(TOTAL_SUPPLY / 100), None, None);
+        assert_eq!(contract.get_pending_redemptions(None, None).len(), 1);
+
+        // Unstaking failed but the callback never ran: the user gets the tokens back
+        testing_env!(context.block_timestamp(3_600_000_000_000).build());
+        contract.reconcile_redemption(0, false);
+        assert!(contract.get_pending_redemptions(None, None).is_empty());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
+        assert_eq!(contract.pool_party_reserve, 10_000);
+        assert_eq!((contract.redemptions.near_out, contract.redemptions.tokens_in), (0, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "A withdrawal from Pool Party is in progress, try again later")]
+    fn test_withdraw_redeemed_in_progress() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.epoch_height(14).build());
+        let mut contract = new_contract(accounts(1));
+        contract.redemptions.push(&accounts(2).into(), pending_withdrawal(100, 14));
+        contract.redemptions.push(&accounts(3).into(), pending_withdrawal(50, 14));
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.withdraw_redeemed();
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.withdraw_redeemed();
+    }
+
+    #[test]
+    fn test_queue_before_raffle() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+
+        // The raffle is in a minute, so the exchange is queued
+        contract.pool_party_reserve = 10_000;
+        contract.pool_party_next_raffle = Some(60_000_000_000);
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(TOTAL_SUPPLY / 100), Some(U128(100)), None);
+
+        let queued = contract.get_queued_exchanges(None, None);
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].amount.0, TOTAL_SUPPLY / 100);
+        assert_eq!(contract.get_exchange_state().circulating_supply.0, TOTAL_SUPPLY);
+        assert!(contract.get_pending_operations(None, None).is_empty());
+
+        // Once the reserve is cached, the queue is settled at the new price
+        set_reserve(&mut contract, 10_000);
+        assert_eq!(contract.process_queue(None), 1);
+        assert!(contract.get_queued_exchanges(None, None).is_empty());
+
+        let pending = contract.get_pending_operations(None, None);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].tickets.0, 100);
+        assert_eq!(contract.pool_party_reserve, 9_900);
+    }
+
+    #[test]
+    fn test_cancel_queued_exchange() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        contract.pool_party_next_raffle = Some(60_000_000_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(1_000), None, None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 1_000);
+
+        contract.cancel_queued_exchange(0);
+        assert!(contract.get_queued_exchanges(None, None).is_empty());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
+    }
+
+    #[test]
+    fn test_queued_near() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        contract.pool_party_next_raffle = Some(60_000_000_000);
+
+        let near = 1_000 * env::storage_byte_cost();
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(near).build());
+        contract.exchange_near_for_tokens(None, None, None);
+        assert_eq!(contract.get_exchange_state().queued_near.0, near);
+
+        testing_env!(context.attached_deposit(0).build());
+        contract.cancel_queued_exchange(0);
+        assert_eq!(contract.get_exchange_state().queued_near.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "to queue the exchange, it pays for its storage")]
+    fn test_queue_near_below_storage() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        contract.pool_party_next_raffle = Some(60_000_000_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(100).build());
+        contract.exchange_near_for_tokens(None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Attach the NEAR to exchange for tokens")]
+    fn test_exchange_no_near() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_near_for_tokens(None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the user can cancel the exchange")]
+    fn test_cancel_queued_exchange_not_user() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        contract.pool_party_next_raffle = Some(60_000_000_000);
+
+        testing_env!(context.prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(1_000), None, None);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.cancel_queued_exchange(0);
+    }
+
+    #[test]
+    fn test_exchange_refreshes_stale_cache() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        // The raffle took place, the exchange waits in the queue for the new reserve
+        testing_env!(context.block_timestamp(3_600_000_000_000).prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(1_000), None, None);
+
+        assert_eq!(contract.get_queued_exchanges(None, None).len(), 1);
+        assert!(contract.get_pending_operations(None, None).is_empty());
+        assert_eq!(contract.queue.tokens_escrowed, 1_000);
+        assert_eq!(contract.get_exchange_state().circulating_supply.0, TOTAL_SUPPLY);
+    }
+
+    #[test]
+    fn test_exchange_after_refresh() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        // Both with a call and with ft_transfer_call, the exchanges wait for the new reserve
+        testing_env!(context.block_timestamp(3_600_000_000_000).prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(1_000), None, None);
+        contract.token.internal_transfer(&accounts(1).into(), &accounts(0).into(), 1_000, None);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.ft_on_transfer(accounts(1), U128(1_000), r#"{"action": "redeem_tickets"}"#.to_string());
+        assert_eq!(contract.queue.tokens_escrowed, 2_000);
+
+        with_promise_result(&context, pool_info(20_000, 7_200_000_000_000, false));
+        contract.exchange_after_refresh_callback(0);
+        with_promise_result(&context, pool_info(20_000, 7_200_000_000_000, false));
+        contract.exchange_after_refresh_callback(1);
+
+        assert!(contract.get_queued_exchanges(None, None).is_empty());
+        assert_eq!(contract.queue.tokens_escrowed, 0);
+        assert_eq!(contract.get_pending_operations(None, None).len(), 2);
+        assert_eq!(contract.pool_party_reserve, 19_996);
+    }
+
+    #[test]
+    fn test_refresh_keeps_queue_order() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        // An exchange is queued right before the raffle, and another one after it
+        testing_env!(context.block_timestamp(3_540_000_000_000).prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(1_000), None, None);
+        testing_env!(context.block_timestamp(3_600_000_000_000).build());
+        contract.exchange_tokens_for_tickets(U128(2_000), None, None);
+
+        // The callback only has gas for one exchange, the oldest goes first
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        with_promise_result(&context, pool_info(20_000, 7_200_000_000_000, false));
+        assert_eq!(contract.exchange_after_refresh_callback(1), 1);
+        assert_eq!(contract.get_pending_operations(None, None)[0].tokens.0, 1_000);
+        assert_eq!(contract.get_queued_exchanges(None, None)[0].id.0, 1);
+    }
+
+    #[test]
+    fn test_refresh_when_paused() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.block_timestamp(3_600_000_000_000).prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(1_000), None, None);
+
+        // The owner paused the exchanges while the refresh was in flight
+        contract.pause(Some(ExchangeDirection::TokensForTickets));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        with_promise_result(&context, pool_info(20_000, 7_200_000_000_000, false));
+        assert_eq!(contract.exchange_after_refresh_callback(0), 0);
+        assert!(contract.get_pending_operations(None, None).is_empty());
+        assert_eq!(contract.get_queued_exchanges(None, None).len(), 1);
+    }
+
+    #[test]
+    fn test_refresh_callback_never_runs() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        testing_env!(context.block_timestamp(3_600_000_000_000).prepaid_gas(300 * TGAS).build());
+        contract.exchange_tokens_for_tickets(U128(1_000), None, None);
+
+        // The callback ran out of gas, the tokens are still recorded in the queue
+        contract.cancel_queued_exchange(0);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
+        assert_eq!(contract.queue.tokens_escrowed, 0);
+
+        // A late callback finds nothing to settle
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        with_promise_result(&context, pool_info(20_000, 7_200_000_000_000, false));
+        contract.exchange_after_refresh_callback(0);
+        assert!(contract.get_pending_operations(None, None).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The refresh callback needs more gas than caching the reserve and settling the exchange")]
+    fn test_refresh_callback_gas_too_low() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+
+        let mut config = contract.get_config();
+        config.refresh_callback_gas = config.cache_callback_gas;
+        contract.update_config(config);
+    }
+
+    // Simulates the callback receiving `result` from Pool Party
+    fn with_promise_result(context: &VMContextBuilder, result: PromiseResult) {
+        testing_env!(context.build(), VMConfig::default(), RuntimeFeesConfig::default(), Default::default(), vec![result]);
+    }
+
+    // Result of `get_pool_info` in Pool Party
+    fn pool_info(reserve: Balance, next_raffle: u64, withdraw_ready: bool) -> PromiseResult {
+        let info = format!(
+            r#"{{"total_staked": "0", "reserve": "{}", "prize": "0", "next_prize_tmstmp": "{}", "withdraw_ready": {}}}"#,
+            reserve, next_raffle, withdraw_ready
+        );
+        PromiseResult::Successful(info.into_bytes())
+    }
+
+    #[test]
+    fn test_promise_result_errors() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        assert_eq!(external::promise_result(0), Err(external::PromiseError::Missing));
+
+        with_promise_result(&context, PromiseResult::Failed);
+        assert_eq!(external::promise_result(0), Err(external::PromiseError::Failed));
+
+        with_promise_result(&context, PromiseResult::NotReady);
+        assert_eq!(external::promise_result(0), Err(external::PromiseError::NotReady));
+
+        with_promise_result(&context, PromiseResult::Successful(b"true".to_vec()));
+        assert_eq!(external::promise_result_json::<bool>(0), Ok(true));
+        assert!(matches!(external::promise_result_json::<PoolInfo>(0), Err(external::PromiseError::Undecodable(_))));
+    }
+
+    #[test]
+    fn test_decode_pool_account() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+
+        let account = br#"{"account_id": "alice", "staked_balance": "100", "unstaked_balance": "5",
+                           "available_when": 42, "available": false}"#;
+        with_promise_result(&context, PromiseResult::Successful(account.to_vec()));
+        let account: PoolAccount = external::promise_result_json(0).unwrap();
+        assert_eq!(account.staked_balance.0, 100);
+        assert_eq!(account.unstaked_balance.0, 5);
+        assert!(!account.available);
+    }
+
+    #[test]
+    fn test_cache_malformed_pool_info() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+        set_reserve(&mut contract, 10_000);
+
+        with_promise_result(&context, PromiseResult::Successful(b"{\"reserve\": \"5\"}".to_vec()));
+        assert!(!contract.cache_pool_party_reserve_callback());
+        assert_eq!(contract.get_exchange_state().reserve.0, 10_000);
+        assert!(near_sdk::test_utils::get_logs().iter().any(|log| log.contains("could not be decoded")));
+    }
+
+    #[test]
+    #[should_panic(expected = "The reserve cache is not initialized, call cache_pool_party_reserve first")]
+    fn test_exchange_before_caching() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = new_contract(accounts(1));
+
+        testing_env!(context.prepaid_gas(300 * TGAS).attached_deposit(10).build());
+        contract.exchange_near_for_tokens(None, None, None);
+    }
+
+    #[test]
+    fn test_new_caches_reserve() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new_default_meta(accounts(1), TOTAL_SUPPLY.into(), accounts(5), Some(true), None);
+        assert_eq!(contract.get_exchange_state().next_raffle, None);
+        assert!(contract.quote_tokens_for_tickets(U128(1)).close_to_raffle);
+    }
+
     #[test]
     #[should_panic(expected = "The contract is not initialized")]
     fn test_default() {
@@ -283,7 +2145,7 @@ mod tests {
     fn test_transfer() {
         let mut context = get_context(accounts(2));
         testing_env!(context.build());
-        let mut contract = Contract::new();
+        let mut contract = new_contract(accounts(2));
         testing_env!(context
             .storage_usage(env::storage_usage())
             .attached_deposit(contract.storage_balance_bounds().min.into())
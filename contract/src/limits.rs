@@ -0,0 +1,120 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+// Tokens bought during a raffle period, identified by the timestamp of its raffle.
+// Amounts of a past period are stale, and overwritten on the next purchase
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct PeriodPurchases {
+    period: u64,
+    tokens: Balance,
+}
+
+impl PeriodPurchases {
+    fn in_period(&self, period: u64) -> Balance {
+        if self.period == period {
+            self.tokens
+        } else {
+            0
+        }
+    }
+
+    fn add(&mut self, period: u64, tokens: Balance) {
+        self.tokens = self.in_period(period) + tokens;
+        self.period = period;
+    }
+
+    fn sub(&mut self, period: u64, tokens: Balance) {
+        self.tokens = self.in_period(period).saturating_sub(tokens);
+        self.period = period;
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PurchaseAllowance {
+    // The raffle that closes the current period, None if the reserve was never cached
+    pub period: Option<U64>,
+    pub bought: U128,
+    // Tokens the account can still buy this period, None if there is no limit
+    pub remaining: Option<U128>,
+    pub min_purchase: Option<U128>,
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_purchase_allowance(&self, account_id: ValidAccountId) -> PurchaseAllowance {
+        let period = self.purchase_period();
+        let bought = self.purchases.get(account_id.as_ref()).map_or(0, |purchases| purchases.in_period(period));
+
+        PurchaseAllowance {
+            period: self.pool_party_next_raffle.map(U64),
+            bought: bought.into(),
+            remaining: self.remaining_purchase(bought).map(U128),
+            min_purchase: self.config.min_purchase,
+        }
+    }
+}
+
+impl Contract {
+    // Purchases are counted per raffle, so limits reset when the cache moves to the next one
+    fn purchase_period(&self) -> u64 {
+        self.pool_party_next_raffle.unwrap_or(0)
+    }
+
+    // Tokens that can still be bought by an account that bought `bought`, the lowest
+    // of the account and the global limits
+    fn remaining_purchase(&self, bought: Balance) -> Option<Balance> {
+        let period = self.purchase_period();
+        let account_remaining = self.config.max_tokens_per_account.map(|max| max.0.saturating_sub(bought));
+        let period_remaining =
+            self.config.max_tokens_per_period.map(|max| max.0.saturating_sub(self.period_sales.in_period(period)));
+
+        match (account_remaining, period_remaining) {
+            (Some(account), Some(period)) => Some(account.min(period)),
+            (account, period) => account.or(period),
+        }
+    }
+
+    // Check the purchase limits, and count the tokens bought by `user`
+    pub(crate) fn record_purchase(&mut self, user: &AccountId, tokens: Balance) -> Result<(), String> {
+        if let Some(min_purchase) = self.config.min_purchase {
+            if tokens < min_purchase.0 {
+                return Err(format!("The purchase is below the minimum of {} tokens", min_purchase.0));
+            }
+        }
+
+        let period = self.purchase_period();
+        let mut purchases = self.purchases.get(user).unwrap_or_default();
+
+        if let Some(remaining) = self.remaining_purchase(purchases.in_period(period)) {
+            if tokens > remaining {
+                return Err(format!("The purchase exceeds the limits of this raffle, {} tokens left", remaining));
+            }
+        }
+
+        purchases.add(period, tokens);
+        self.purchases.insert(user, &purchases);
+        self.period_sales.add(period, tokens);
+        Ok(())
+    }
+
+    // Give back the allowance of a purchase that failed. Exchanges cannot run close to the
+    // raffle, so the purchase almost surely belongs to the current period
+    pub(crate) fn release_purchase(&mut self, user: &AccountId, tokens: Balance) {
+        let period = self.purchase_period();
+
+        if let Some(mut purchases) = self.purchases.get(user) {
+            purchases.sub(period, tokens);
+            if purchases.tokens == 0 {
+                self.purchases.remove(user);
+            } else {
+                self.purchases.insert(user, &purchases);
+            }
+        }
+        self.period_sales.sub(period, tokens);
+    }
+}
@@ -0,0 +1,19 @@
+use near_sdk::env;
+use near_sdk::json_types::Base64VecU8;
+
+// Merkle trees of the allowlist and the airdrops. Each node is the sha256 of its two
+// children, the smallest first, so a proof is only the list of siblings from the leaf up
+
+pub(crate) fn assert_valid_root(root: &[u8]) {
+    assert!(root.len() == 32, "The Merkle root must be a sha256 hash");
+}
+
+// Whether `proof` leads from `leaf` to `root`
+pub(crate) fn verify(leaf: Vec<u8>, proof: Vec<Base64VecU8>, root: &[u8]) -> bool {
+    proof.into_iter().fold(leaf, |node, sibling| hash_pair(node, sibling.into())) == root
+}
+
+fn hash_pair(first: Vec<u8>, second: Vec<u8>) -> Vec<u8> {
+    let (low, high) = if first <= second { (first, second) } else { (second, first) };
+    env::sha256(&[low, high].concat())
+}
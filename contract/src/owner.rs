@@ -0,0 +1,44 @@
+use near_sdk::json_types::ValidAccountId;
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId};
+
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn get_proposed_owner(&self) -> Option<AccountId> {
+        self.proposed_owner_id.clone()
+    }
+
+    // Ownership is transferred in two steps, so a typo cannot lock the contract:
+    // the owner proposes a new account, and that account has to accept it.
+    // Both steps require a full access key, by attaching 1 yoctoNEAR
+    #[payable]
+    pub fn propose_owner(&mut self, new_owner_id: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        let new_owner_id: AccountId = new_owner_id.into();
+        log!("Proposing @{} as new owner", new_owner_id);
+        self.proposed_owner_id = Some(new_owner_id);
+    }
+
+    #[payable]
+    pub fn accept_owner(&mut self) {
+        assert_one_yocto();
+        let caller: AccountId = env::predecessor_account_id();
+        assert!(self.proposed_owner_id.as_ref() == Some(&caller), "Only the proposed owner can accept");
+
+        log!("Ownership transferred from @{} to @{}", self.owner_id, caller);
+        self.owner_id = caller;
+        self.proposed_owner_id = None;
+    }
+}
+
+impl Contract {
+    pub(crate) fn assert_owner(&self) {
+        assert!(env::predecessor_account_id() == self.owner_id, "Only the owner can call this method");
+    }
+}
@@ -0,0 +1,67 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::near_bindgen;
+
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum ExchangeDirection {
+    NearForTokens,
+    TokensForTickets,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Default, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseStatus {
+    pub near_for_tokens: bool,
+    pub tokens_for_tickets: bool,
+}
+
+impl PauseStatus {
+    fn set(&mut self, direction: Option<ExchangeDirection>, value: bool) {
+        match direction {
+            Some(ExchangeDirection::NearForTokens) => self.near_for_tokens = value,
+            Some(ExchangeDirection::TokensForTickets) => self.tokens_for_tickets = value,
+            None => {
+                self.near_for_tokens = value;
+                self.tokens_for_tickets = value;
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn is_paused(&self) -> PauseStatus {
+        self.paused.clone()
+    }
+
+    // Stop the exchanges in the given direction, or in both if none is given.
+    // Plain NEP-141 transfers are never paused
+    pub fn pause(&mut self, direction: Option<ExchangeDirection>) {
+        self.assert_owner();
+        self.paused.set(direction, true);
+        events::paused(direction, &self.paused);
+    }
+
+    pub fn unpause(&mut self, direction: Option<ExchangeDirection>) {
+        self.assert_owner();
+        self.paused.set(direction, false);
+        events::unpaused(direction, &self.paused);
+    }
+}
+
+impl Contract {
+    pub(crate) fn is_paused_for(&self, direction: ExchangeDirection) -> bool {
+        match direction {
+            ExchangeDirection::NearForTokens => self.paused.near_for_tokens,
+            ExchangeDirection::TokensForTickets => self.paused.tokens_for_tickets,
+        }
+    }
+
+    pub(crate) fn panic_if_paused(&self, direction: ExchangeDirection) {
+        assert!(!self.is_paused_for(direction), "The exchange is paused, try again later");
+    }
+}
@@ -0,0 +1,125 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+// An exchange waiting for its callback
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingOperation {
+    pub id: U64,
+    pub user: AccountId,
+    pub tokens: U128,
+    // For NearForTokens, the NEAR being staked in Pool Party
+    pub tickets: U128,
+    // For NearForTokens, the sale fee paid on top of the staked NEAR
+    pub fee: U128,
+    pub direction: ExchangeDirection,
+    // Tokens sent with `ft_transfer_call` are given back by `ft_resolve_transfer`
+    pub via_transfer_call: bool,
+    pub started_at: U64,
+}
+
+// Exchanges with promises in flight. Their amounts are already taken from the
+// cached reserve and the contract inventory, but not yet confirmed by Pool Party
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PendingLedger {
+    operations: UnorderedMap<u64, PendingOperation>,
+    next_id: u64,
+    // Tickets being given from the reserve, and the tokens paid for them
+    pub tickets_out: Balance,
+    pub tokens_in: Balance,
+    // NEAR being staked in Pool Party
+    pub near_in: Balance,
+    // Tokens being sold
+    pub tokens_out: Balance,
+}
+
+impl PendingLedger {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            operations: UnorderedMap::new(prefix),
+            next_id: 0,
+            tickets_out: 0,
+            tokens_in: 0,
+            near_in: 0,
+            tokens_out: 0,
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        direction: ExchangeDirection,
+        user: &AccountId,
+        tokens: Balance,
+        tickets: Balance,
+        fee: Balance,
+        via_transfer_call: bool,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        match direction {
+            ExchangeDirection::TokensForTickets => {
+                self.tickets_out += tickets;
+                self.tokens_in += tokens;
+            }
+            ExchangeDirection::NearForTokens => {
+                self.near_in += tickets;
+                self.tokens_out += tokens;
+            }
+        }
+
+        let operation = PendingOperation {
+            id: id.into(),
+            user: user.clone(),
+            tokens: tokens.into(),
+            tickets: tickets.into(),
+            fee: fee.into(),
+            direction,
+            via_transfer_call,
+            started_at: env::block_timestamp().into(),
+        };
+        self.operations.insert(&id, &operation);
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<PendingOperation> {
+        self.operations.get(&id)
+    }
+
+    pub fn finish(&mut self, id: u64) -> Option<PendingOperation> {
+        let operation = self.operations.remove(&id)?;
+
+        match operation.direction {
+            ExchangeDirection::TokensForTickets => {
+                self.tickets_out -= operation.tickets.0;
+                self.tokens_in -= operation.tokens.0;
+            }
+            ExchangeDirection::NearForTokens => {
+                self.near_in -= operation.tickets.0;
+                self.tokens_out -= operation.tokens.0;
+            }
+        }
+        Some(operation)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Operations still waiting for their callback, stuck ones never leave this list
+    pub fn get_pending_operations(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<PendingOperation> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.pending.operations.len());
+
+        self.pending
+            .operations
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+}
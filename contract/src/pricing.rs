@@ -0,0 +1,156 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, Balance};
+
+use crate::*;
+
+// Fixed point precision of the exponential growth factor
+const ONE: u128 = 1_000_000_000_000_000_000;
+const BPS_DENOMINATOR: u128 = 10_000;
+
+// How the contract prices the tokens it sells. Curve prices are in yoctoNEAR per
+// token unit, and rise as the tokens of the contract are sold
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum PricingMode {
+    // price = reserve / circulating_supply
+    Flat,
+    // price = base_price + (max_price - base_price) * sold / total_supply
+    Linear { base_price: U128, max_price: U128 },
+    // price = base_price * (1 + growth_bps / 10_000) ^ (sold / step)
+    Exponential { base_price: U128, growth_bps: u32, step: U128 },
+}
+
+impl PricingMode {
+    pub fn assert_valid(&self, total_supply: Balance) {
+        match self {
+            PricingMode::Flat => return,
+            PricingMode::Linear { base_price, max_price } => {
+                assert!(base_price.0 > 0, "The base price must be positive");
+                assert!(max_price.0 >= base_price.0, "The max price cannot be lower than the base price");
+            }
+            PricingMode::Exponential { base_price, growth_bps, step } => {
+                assert!(base_price.0 > 0, "The base price must be positive");
+                assert!(*growth_bps > 0 && step.0 > 0, "The price must grow every step");
+            }
+        }
+
+        let max_price = self.checked_price_at(total_supply, total_supply);
+        assert!(max_price.map_or(false, |price| price <= U256::from(u128::MAX))
+                && self.cumulative_cost(total_supply, total_supply).is_some(),
+                "The price grows too much to sell the total supply");
+    }
+
+    // Price of the next token unit, after `sold` units were sold
+    pub fn price_at(&self, sold: Balance, total_supply: Balance) -> Balance {
+        u256_to_balance(self.checked_price_at(sold, total_supply).expect("The price does not fit in a u256"))
+    }
+
+    fn checked_price_at(&self, sold: Balance, total_supply: Balance) -> Option<U256> {
+        match self {
+            PricingMode::Flat => panic!("The flat price depends on the reserve"),
+            PricingMode::Linear { base_price, max_price } => {
+                let slope = U256::from(max_price.0 - base_price.0);
+                Some(U256::from(base_price.0) + slope * U256::from(sold) / U256::from(total_supply))
+            }
+            PricingMode::Exponential { base_price, growth_bps, step } => {
+                let factor = checked_pow(growth_factor(*growth_bps), sold / step.0)?;
+                Some(U256::from(base_price.0).checked_mul(factor)? / U256::from(ONE))
+            }
+        }
+    }
+
+    // Tokens that `near_amount` buys after `sold` units were sold, paying the integral
+    // of the price. Costs only grow with the amount, so we search the largest one we can pay
+    pub fn tokens_for_near(&self, sold: Balance, near_amount: Balance, total_supply: Balance) -> Balance {
+        let available = total_supply.saturating_sub(sold);
+        let near_amount = U256::from(near_amount);
+        let start = self.expect_cumulative_cost(sold, total_supply);
+
+        let (mut low, mut high) = (0, available);
+        while low < high {
+            let middle = low + (high - low + 1) / 2;
+            if self.expect_cumulative_cost(sold + middle, total_supply) - start <= near_amount {
+                low = middle;
+            } else {
+                high = middle - 1;
+            }
+        }
+        low
+    }
+
+    fn expect_cumulative_cost(&self, sold: Balance, total_supply: Balance) -> U256 {
+        self.cumulative_cost(sold, total_supply).expect("The cost does not fit in a u256")
+    }
+
+    // NEAR paid to sell the first `sold` units, None if it overflows
+    fn cumulative_cost(&self, sold: Balance, total_supply: Balance) -> Option<U256> {
+        match self {
+            PricingMode::Flat => panic!("The flat price depends on the reserve"),
+            // base_price * sold + (max_price - base_price) * sold^2 / (2 * total_supply)
+            PricingMode::Linear { base_price, max_price } => {
+                let sold = U256::from(sold);
+                let slope = U256::from(max_price.0 - base_price.0);
+                let growth = slope.checked_mul(sold)?.checked_mul(sold)? / (U256::from(total_supply) * 2);
+                (U256::from(base_price.0) * sold).checked_add(growth)
+            }
+            // Full steps are a geometric series, plus the part of the current step
+            // base_price * step * (growth^steps - 1) / (growth - 1) + base_price * growth^steps * rest
+            PricingMode::Exponential { base_price, growth_bps, step } => {
+                let growth = growth_factor(*growth_bps);
+                let factor = checked_pow(growth, sold / step.0)?;
+                let rest = U256::from(sold % step.0);
+                let base_price = U256::from(base_price.0);
+
+                let one = U256::from(ONE);
+                let full_steps = base_price.checked_mul(U256::from(step.0))?.checked_mul(factor - one)? / (growth - one);
+                let current_step = base_price.checked_mul(factor)?.checked_mul(rest)? / one;
+                full_steps.checked_add(current_step)
+            }
+        }
+    }
+}
+
+// 1 + growth_bps / 10_000, in fixed point
+fn growth_factor(growth_bps: u32) -> U256 {
+    U256::from(ONE) + U256::from(ONE) * U256::from(growth_bps) / U256::from(BPS_DENOMINATOR)
+}
+
+// `factor` ^ `exponent`, both in fixed point, None if it overflows
+fn checked_pow(mut factor: U256, mut exponent: u128) -> Option<U256> {
+    let mut result = U256::from(ONE);
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.checked_mul(factor)? / U256::from(ONE);
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            factor = factor.checked_mul(factor)? / U256::from(ONE);
+        }
+    }
+    Some(result)
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_pricing_mode(&self) -> PricingMode {
+        self.pricing.clone()
+    }
+}
+
+impl Contract {
+    // Tokens the contract cannot sell, the curves price them as sold
+    pub(crate) fn tokens_sold(&self) -> Balance {
+        self.token.total_supply - self.tokens_for_sale()
+    }
+
+    // Price of the next token unit the contract sells, in yoctoNEAR
+    pub(crate) fn marginal_price(&self) -> Balance {
+        match self.pricing {
+            PricingMode::Flat => self.pool_party_reserve.checked_div(self.circulating_supply()).unwrap_or(0),
+            _ => self.pricing.price_at(self.tokens_sold(), self.token.total_supply),
+        }
+    }
+}
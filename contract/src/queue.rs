@@ -0,0 +1,200 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Balance, Gas};
+
+use crate::*;
+
+// Gas used by `process_queue` to settle an exchange, besides the gas it attaches
+pub(crate) const SETTLE_GAS: Gas = 10 * TGAS;
+
+// An exchange requested right before the raffle, waiting for the new reserve
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QueuedExchange {
+    pub id: U64,
+    pub user: AccountId,
+    pub direction: ExchangeDirection,
+    // NEAR for NearForTokens, tokens for TokensForTickets
+    pub amount: U128,
+    pub min_out: Option<U128>,
+    pub deadline: Option<U64>,
+    pub queued_at: U64,
+}
+
+// FIFO queue of exchanges, the NEAR and tokens are escrowed by the contract
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ExchangeQueue {
+    entries: LookupMap<u64, QueuedExchange>,
+    head: u64,
+    next_id: u64,
+    pub near_escrowed: Balance,
+    pub tokens_escrowed: Balance,
+}
+
+impl ExchangeQueue {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self { entries: LookupMap::new(prefix), head: 0, next_id: 0, near_escrowed: 0, tokens_escrowed: 0 }
+    }
+
+    pub fn push(
+        &mut self,
+        direction: ExchangeDirection,
+        user: &AccountId,
+        amount: Balance,
+        min_out: Option<U128>,
+        deadline: Option<U64>,
+    ) -> QueuedExchange {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.hold(direction, amount);
+
+        let entry = QueuedExchange {
+            id: id.into(),
+            user: user.clone(),
+            direction,
+            amount: amount.into(),
+            min_out,
+            deadline,
+            queued_at: env::block_timestamp().into(),
+        };
+        self.entries.insert(&id, &entry);
+        entry
+    }
+
+    pub fn get(&self, id: u64) -> Option<QueuedExchange> {
+        self.entries.get(&id)
+    }
+
+    // Oldest exchange in the queue, skipping the cancelled ones
+    pub fn front(&mut self) -> Option<QueuedExchange> {
+        while self.head < self.next_id {
+            if let Some(entry) = self.entries.get(&self.head) {
+                return Some(entry);
+            }
+            self.head += 1;
+        }
+        None
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<QueuedExchange> {
+        let entry = self.entries.remove(&id)?;
+        self.release(entry.direction, entry.amount.0);
+        Some(entry)
+    }
+
+    // Escrow NEAR or tokens that belong to a user, e.g. while the exchange waits in the queue
+    pub fn hold(&mut self, direction: ExchangeDirection, amount: Balance) {
+        match direction {
+            ExchangeDirection::NearForTokens => self.near_escrowed += amount,
+            ExchangeDirection::TokensForTickets => self.tokens_escrowed += amount,
+        }
+    }
+
+    pub fn release(&mut self, direction: ExchangeDirection, amount: Balance) {
+        match direction {
+            ExchangeDirection::NearForTokens => self.near_escrowed -= amount,
+            ExchangeDirection::TokensForTickets => self.tokens_escrowed -= amount,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_queued_exchanges(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<QueuedExchange> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(u64::MAX);
+
+        (self.queue.head..self.queue.next_id)
+            .filter_map(|id| self.queue.get(id))
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    // Settle the queued exchanges, oldest first, at the price of the new raffle period.
+    // Processes up to `limit` exchanges, as long as the attached gas allows it
+    pub fn process_queue(&mut self, limit: Option<u64>) -> u64 {
+        assert!(!self.is_close_to_raffle(), "The reserve has not been updated for the new raffle yet");
+        self.settle_queue(limit.unwrap_or(u64::MAX))
+    }
+
+    pub fn cancel_queued_exchange(&mut self, id: u64) {
+        let entry = self.queue.get(id).expect("Queued exchange not found");
+        assert!(entry.user == env::predecessor_account_id(), "Only the user can cancel the exchange");
+
+        self.queue.remove(id);
+        self.return_queued(&entry, "Cancelled by the user");
+    }
+}
+
+impl Contract {
+    // Settle up to `limit` exchanges from the head of the queue, while the gas lasts
+    pub(crate) fn settle_queue(&mut self, limit: u64) -> u64 {
+        let mut processed = 0;
+
+        while processed < limit {
+            let entry = match self.queue.front() {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            // Keep the order: wait for the exchange to be unpaused, or for more gas
+            let remaining_gas = env::prepaid_gas() - env::used_gas();
+            if self.is_paused_for(entry.direction) || remaining_gas < self.exchange_gas(entry.direction) + SETTLE_GAS {
+                break;
+            }
+
+            self.queue.remove(entry.id.0);
+            self.settle_queued(entry);
+            processed += 1;
+        }
+
+        processed
+    }
+
+    pub(crate) fn enqueue(
+        &mut self,
+        direction: ExchangeDirection,
+        user: &AccountId,
+        amount: Balance,
+        min_out: Option<U128>,
+        deadline: Option<U64>,
+    ) -> QueuedExchange {
+        let storage_before = env::storage_usage();
+        let entry = self.queue.push(direction, user, amount, min_out, deadline);
+
+        // The escrowed NEAR stays in the balance of the contract, paying for the entry
+        if direction == ExchangeDirection::NearForTokens {
+            let storage_cost = Balance::from(env::storage_usage() - storage_before) * env::storage_byte_cost();
+            assert!(amount >= storage_cost,
+                    "Attach at least {} yoctoNEAR to queue the exchange, it pays for its storage", storage_cost);
+        }
+
+        events::exchange_queued(&entry);
+        entry
+    }
+
+    // Start an exchange taken out of the queue, or give the funds back if it cannot run
+    fn settle_queued(&mut self, entry: QueuedExchange) {
+        let outcome = self.try_start_exchange(
+            entry.direction,
+            entry.user.clone(),
+            entry.amount.0,
+            entry.min_out,
+            entry.deadline,
+            false,
+        );
+
+        if let Err(reason) = outcome {
+            self.return_queued(&entry, &reason);
+        }
+    }
+
+    // Give back the escrowed NEAR or tokens of an exchange removed from the queue
+    fn return_queued(&mut self, entry: &QueuedExchange, reason: &str) {
+        self.return_escrow(entry.direction, &entry.user, entry.amount.0);
+        events::queued_exchange_removed(entry, reason);
+    }
+}
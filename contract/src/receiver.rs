@@ -0,0 +1,85 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, serde_json, AccountId, Balance, PromiseOrValue};
+
+use crate::*;
+
+// Actions that can be requested by calling `ft_transfer_call` on this contract,
+// with this contract as receiver, e.g. `{"action": "redeem_tickets", "min_tickets": "10"}`
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TransferMessage {
+    RedeemTickets { min_tickets: Option<U128>, deadline: Option<U64> },
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    // The tokens are already in the balance of this contract. If the exchange
+    // fails they are given back by `ft_resolve_transfer`
+    fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
+        assert!(env::predecessor_account_id() == env::current_account_id(), "Only $POOL tokens can be transferred");
+
+        let message: TransferMessage = serde_json::from_str(&msg).expect("Unknown message");
+
+        match message {
+            TransferMessage::RedeemTickets { min_tickets, deadline } => {
+                self.redeem_tickets(sender_id.into(), amount.0, min_tickets, deadline)
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[private]
+    pub fn redeem_tickets_callback(&mut self, operation_id: u64, user: AccountId, tokens: Balance, tickets: Balance) -> U128 {
+        // `reconcile_pending` assumes `ft_resolve_transfer` gave the tokens back, as it
+        // does when this callback fails, so a delayed callback returns them too
+        if self.pending.finish(operation_id).is_none() {
+            log!("Operation {} was already reconciled, returning the tokens to {}", operation_id, &user);
+            return U128(tokens);
+        }
+
+        if let Err(error) = external::promise_result(0) {
+            log!("Giving the tickets failed: {}. Returning tokens to {}", error, &user);
+            self.pool_party_reserve += tickets;
+            self.treasury.record_tokens_refund(tokens);
+            events::exchange_tokens_for_tickets_refund(&user, tokens, tickets);
+            return U128(tokens);
+        }
+
+        self.treasury.record_tickets(tokens, tickets);
+        events::exchange_tokens_for_tickets(&user, tokens, tickets);
+        U128(0)
+    }
+}
+
+impl Contract {
+    fn redeem_tickets(
+        &mut self,
+        user: AccountId,
+        amount_tokens: Balance,
+        min_tickets: Option<U128>,
+        deadline: Option<U64>,
+    ) -> PromiseOrValue<U128> {
+        self.panic_if_cannot_exchange(ExchangeDirection::TokensForTickets, deadline);
+
+        // Queued tokens are kept, and given back if the exchange is cancelled. With an
+        // outdated cache, the exchange is settled once Pool Party returns the new reserve
+        if self.is_cache_stale() {
+            self.refresh_then_exchange(ExchangeDirection::TokensForTickets, &user, amount_tokens, min_tickets, deadline);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        if self.is_close_to_raffle() {
+            self.enqueue(ExchangeDirection::TokensForTickets, &user, amount_tokens, min_tickets, deadline);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        self.try_start_exchange(ExchangeDirection::TokensForTickets, user, amount_tokens, min_tickets, deadline, true)
+            .unwrap_or_else(|error| panic!("{}", error))
+            .into()
+    }
+}
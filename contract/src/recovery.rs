@@ -0,0 +1,106 @@
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, Promise};
+
+use crate::*;
+
+// Callbacks run a few blocks after their exchange starts. Operations are only
+// reconciled long after that, once their callbacks certainly ran out or failed
+const RECONCILE_DELAY: u64 = 3_600_000_000_000; // 1 hour
+
+#[near_bindgen]
+impl Contract {
+    // NEAR that could not be sent back to `account_id`, and can be claimed
+    pub fn get_claimable_refund(&self, account_id: ValidAccountId) -> U128 {
+        self.claimable_refunds.get(account_id.as_ref()).unwrap_or(0).into()
+    }
+
+    pub fn claim_refund(&mut self) -> Promise {
+        let user: AccountId = env::predecessor_account_id();
+        let amount = self.claimable_refunds.remove(&user).unwrap_or(0);
+        assert!(amount > 0, "There is nothing to claim");
+
+        events::refund_claimed(&user, amount);
+        self.refund(user, amount)
+    }
+
+    #[private]
+    pub fn refund_callback(&mut self, user: AccountId, amount: Balance) -> bool {
+        if let Err(error) = external::promise_result(0) {
+            log!("Failed to send {} back to {}: {}. It can be claimed later", amount, &user, error);
+            self.add_claimable_refund(&user, amount);
+            return false;
+        }
+        true
+    }
+
+    // Resolves an exchange whose callback never ran, e.g. because it ran out of gas.
+    // The owner checks in Pool Party whether the exchange `succeeded`. Tokens sent with
+    // `ft_transfer_call` were already given back by `ft_resolve_transfer` when the callback failed
+    pub fn reconcile_pending(&mut self, operation_id: u64, succeeded: bool) {
+        self.assert_owner();
+
+        let operation = self.pending.get(operation_id).expect("Pending operation not found");
+        self.assert_can_reconcile(operation.started_at.0);
+        self.pending.finish(operation_id);
+        let (user, tokens, tickets, fee) =
+            (operation.user.clone(), operation.tokens.0, operation.tickets.0, operation.fee.0);
+        let this: AccountId = env::current_account_id();
+
+        match (operation.direction, succeeded) {
+            (ExchangeDirection::TokensForTickets, true) => {
+                // The user got the tickets, so the returned tokens are taken back
+                if operation.via_transfer_call {
+                    assert!(self.token.internal_unwrap_balance_of(&user) >= tokens,
+                            "The user no longer has the tokens returned by ft_resolve_transfer");
+                    self.internal_transfer(&user, &this, tokens, None);
+                }
+                self.treasury.record_tickets(tokens, tickets);
+            }
+            (ExchangeDirection::TokensForTickets, false) => {
+                self.pool_party_reserve += tickets;
+                self.treasury.record_tokens_refund(tokens);
+                if !operation.via_transfer_call {
+                    self.internal_transfer(&this, &user, tokens, None);
+                }
+            }
+            (ExchangeDirection::NearForTokens, true) => {
+                self.internal_transfer(&this, &user, tokens, None);
+                self.pool_party_reserve += tickets;
+                self.treasury.record_sale(tickets, tokens);
+                self.collect_fee(fee);
+            }
+            (ExchangeDirection::NearForTokens, false) => {
+                self.release_purchase(&user, tokens);
+                self.release_allowlist(&user, tickets + fee);
+                self.treasury.record_near_refund(tickets + fee);
+                self.add_claimable_refund(&user, tickets + fee);
+            }
+        }
+
+        events::reconcile_pending(&operation, succeeded);
+    }
+}
+
+impl Contract {
+    pub(crate) fn assert_can_reconcile(&self, started_at: u64) {
+        assert!(env::block_timestamp() >= started_at + RECONCILE_DELAY,
+                "The operation can only be reconciled an hour after it started");
+    }
+
+    // Sends NEAR to the user, keeping it claimable if the transfer fails
+    pub(crate) fn refund(&mut self, user: AccountId, amount: Balance) -> Promise {
+        Promise::new(user.clone()).transfer(amount).then(this_contract::refund_callback(
+            user,
+            amount,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.refund_callback_gas.0,
+        ))
+    }
+
+    pub(crate) fn add_claimable_refund(&mut self, user: &AccountId, amount: Balance) {
+        let claimable = self.claimable_refunds.get(user).unwrap_or(0);
+        self.claimable_refunds.insert(user, &(claimable + amount));
+        events::refund_recorded(user, amount);
+    }
+}
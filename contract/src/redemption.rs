@@ -0,0 +1,415 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, Promise, PromiseOrValue};
+
+use crate::*;
+
+// NEAR unstaked from Pool Party for a user who redeemed tokens
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingWithdrawal {
+    pub near: U128,
+    pub tokens: U128,
+    // The `available_when` of Pool Party after unstaking
+    pub withdrawable_at_epoch: U64,
+}
+
+// A redemption waiting for Pool Party to unstake its NEAR
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingRedemption {
+    pub id: U64,
+    pub user: AccountId,
+    pub tokens: U128,
+    pub near: U128,
+    pub started_at: U64,
+}
+
+// A `withdraw_all` from Pool Party in flight, and the redemptions of the user it pays
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalInProgress {
+    pub id: U64,
+    pub user: AccountId,
+    pub withdrawals: Vec<PendingWithdrawal>,
+    pub started_at: U64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct RedemptionLedger {
+    withdrawals: LookupMap<AccountId, Vec<PendingWithdrawal>>,
+    unstaking: UnorderedMap<u64, PendingRedemption>,
+    next_id: u64,
+    // Redemptions waiting for Pool Party to unstake: the NEAR taken from the
+    // cached reserve, and the tokens paid for it
+    pub near_out: Balance,
+    pub tokens_in: Balance,
+    // NEAR already withdrawn from Pool Party to this contract, not paid yet
+    pub near_withdrawn: Balance,
+    // Only one `withdraw_all` can be in flight, or we would count the NEAR twice
+    pub withdrawing: Option<WithdrawalInProgress>,
+}
+
+impl RedemptionLedger {
+    pub fn new(withdrawals_prefix: Vec<u8>, unstaking_prefix: Vec<u8>) -> Self {
+        Self {
+            withdrawals: LookupMap::new(withdrawals_prefix),
+            unstaking: UnorderedMap::new(unstaking_prefix),
+            next_id: 0,
+            near_out: 0,
+            tokens_in: 0,
+            near_withdrawn: 0,
+            withdrawing: None,
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn start_unstake(&mut self, user: &AccountId, tokens: Balance, near: Balance) -> u64 {
+        let id = self.next_id();
+        self.near_out += near;
+        self.tokens_in += tokens;
+
+        let redemption = PendingRedemption {
+            id: id.into(),
+            user: user.clone(),
+            tokens: tokens.into(),
+            near: near.into(),
+            started_at: env::block_timestamp().into(),
+        };
+        self.unstaking.insert(&id, &redemption);
+        id
+    }
+
+    pub fn get_unstake(&self, id: u64) -> Option<PendingRedemption> {
+        self.unstaking.get(&id)
+    }
+
+    pub fn finish_unstake(&mut self, id: u64) -> Option<PendingRedemption> {
+        let redemption = self.unstaking.remove(&id)?;
+        self.near_out -= redemption.near.0;
+        self.tokens_in -= redemption.tokens.0;
+        Some(redemption)
+    }
+
+    pub fn start_withdrawal(&mut self, user: &AccountId, withdrawals: Vec<PendingWithdrawal>) -> u64 {
+        let id = self.next_id();
+        self.withdrawing = Some(WithdrawalInProgress {
+            id: id.into(),
+            user: user.clone(),
+            withdrawals,
+            started_at: env::block_timestamp().into(),
+        });
+        id
+    }
+
+    // The withdrawal in flight, if it is still `id` and was not reconciled
+    fn withdrawal(&self, id: u64) -> Option<WithdrawalInProgress> {
+        self.withdrawing.clone().filter(|withdrawal| withdrawal.id.0 == id)
+    }
+
+    pub fn get(&self, user: &AccountId) -> Vec<PendingWithdrawal> {
+        self.withdrawals.get(user).unwrap_or_default()
+    }
+
+    pub fn push(&mut self, user: &AccountId, withdrawal: PendingWithdrawal) {
+        let mut withdrawals = self.get(user);
+        withdrawals.push(withdrawal);
+        self.withdrawals.insert(user, &withdrawals);
+    }
+
+    // Take out the withdrawals of `user` that finished unbonding
+    pub fn take_ready(&mut self, user: &AccountId) -> Vec<PendingWithdrawal> {
+        let (ready, waiting): (Vec<_>, Vec<_>) =
+            self.get(user).into_iter().partition(|withdrawal| withdrawal.withdrawable_at_epoch.0 <= env::epoch_height());
+
+        if waiting.is_empty() {
+            self.withdrawals.remove(user);
+        } else {
+            self.withdrawals.insert(user, &waiting);
+        }
+        ready
+    }
+
+    // Put back withdrawals taken out by `take_ready`, when they could not be paid
+    pub fn restore(&mut self, user: &AccountId, mut withdrawals: Vec<PendingWithdrawal>) {
+        withdrawals.extend(self.get(user));
+        self.withdrawals.insert(user, &withdrawals);
+    }
+}
+
+fn total_near(withdrawals: &[PendingWithdrawal]) -> Balance {
+    withdrawals.iter().map(|withdrawal| withdrawal.near.0).sum()
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_pending_withdrawals(&self, account_id: ValidAccountId) -> Vec<PendingWithdrawal> {
+        self.redemptions.get(account_id.as_ref())
+    }
+
+    // Redemptions still waiting for their unstake callback, stuck ones never leave this list
+    pub fn get_pending_redemptions(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<PendingRedemption> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.redemptions.unstaking.len());
+
+        self.redemptions
+            .unstaking
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_withdrawal_in_progress(&self) -> Option<WithdrawalInProgress> {
+        self.redemptions.withdrawing.clone()
+    }
+
+    // Redeem $POOL tokens for NEAR, unstaking their share of the reserve from Pool Party.
+    // The NEAR can be taken with `withdraw_redeemed` once the validator releases it
+    pub fn redeem_tokens_for_near(&mut self, amount_tokens: U128, min_near_out: Option<U128>, deadline: Option<U64>) -> Promise {
+        let required_gas = self.config.redeem_prepaid_gas.0;
+        assert!(env::prepaid_gas() >= required_gas, "This method requires at least {} TGAS to run", required_gas / TGAS);
+
+        // Redemptions take from the reserve, same as exchanging tokens for tickets
+        self.panic_if_paused(ExchangeDirection::TokensForTickets);
+        self.panic_if_past_deadline(deadline);
+        assert!(!self.is_close_to_raffle(), "Redemptions are closed until the reserve is updated after the raffle");
+
+        let amount_tokens = u128::from(amount_tokens);
+        let user: AccountId = env::predecessor_account_id();
+        let this: AccountId = env::current_account_id();
+
+        // Tickets are NEAR staked 1:1, so the tokens are worth the same NEAR as tickets
+        let amount_near = self.tickets_for_tokens(amount_tokens, self.circulating_supply());
+        assert!(amount_near > 0, "The amount is too small to redeem any NEAR");
+        if let Some(min_near_out) = min_near_out {
+            assert!(amount_near >= min_near_out.0,
+                    "Slippage: would get {} yoctoNEAR, less than the minimum {}", amount_near, min_near_out.0);
+        }
+
+        self.internal_transfer(&user, &this, amount_tokens, None);
+        self.pool_party_reserve -= amount_near;
+        let redemption_id = self.redemptions.start_unstake(&user, amount_tokens, amount_near);
+
+        let callback_gas = self.config.get_pool_info_gas.0 + self.config.redeem_callback_gas.0 + config::CHECK_CALLBACK_GAS;
+
+        poolparty_contract::unstake(U128(amount_near), &self.pool_party_account, NO_DEPOSIT, self.config.unstake_gas.0)
+            .then(this_contract::redeem_tokens_for_near_callback(redemption_id, &this, NO_DEPOSIT, callback_gas))
+    }
+
+    #[private]
+    pub fn redeem_tokens_for_near_callback(&mut self, redemption_id: u64) -> PromiseOrValue<bool> {
+        // The owner may have reconciled the redemption while the callback was delayed
+        let redemption = match self.redemptions.get_unstake(redemption_id) {
+            Some(redemption) => redemption,
+            None => {
+                log!("Redemption {} was already reconciled, ignoring its callback", redemption_id);
+                return PromiseOrValue::Value(false);
+            }
+        };
+
+        if let Err(error) = external::promise_result(0) {
+            log!("Unstaking failed: {}. Returning tokens to {}", error, &redemption.user);
+            self.redemptions.finish_unstake(redemption_id);
+            self.refund_redemption(&redemption.user, redemption.tokens.0, redemption.near.0);
+            return PromiseOrValue::Value(false);
+        }
+
+        // Ask Pool Party from which epoch the unstaked NEAR can be withdrawn
+        let this: AccountId = env::current_account_id();
+        poolparty_contract::get_account(this.clone(), &self.pool_party_account, NO_DEPOSIT, self.config.get_pool_info_gas.0)
+            .then(this_contract::redeem_unstaked_callback(
+                redemption_id,
+                &this,
+                NO_DEPOSIT,
+                self.config.redeem_callback_gas.0,
+            ))
+            .into()
+    }
+
+    #[private]
+    pub fn redeem_unstaked_callback(&mut self, redemption_id: u64) -> bool {
+        let redemption = match self.redemptions.finish_unstake(redemption_id) {
+            Some(redemption) => redemption,
+            None => {
+                log!("Redemption {} was already reconciled, ignoring its callback", redemption_id);
+                return false;
+            }
+        };
+
+        // Pool Party is asked again whether the NEAR is available before withdrawing it,
+        // so without its answer the redemption can be withdrawn from now on
+        let available_when = match external::promise_result_json::<PoolAccount>(0) {
+            Ok(account) => account.available_when,
+            Err(error) => {
+                log!("Getting the account from Pool Party failed: {}", error);
+                env::epoch_height()
+            }
+        };
+
+        self.record_redemption(&redemption.user, redemption.tokens.0, redemption.near.0, available_when);
+        true
+    }
+
+    // Resolves a redemption whose callback never ran. The owner checks in Pool Party
+    // whether the NEAR was unstaked, i.e. the redemption `succeeded`
+    pub fn reconcile_redemption(&mut self, redemption_id: u64, succeeded: bool) {
+        self.assert_owner();
+
+        let redemption = self.redemptions.get_unstake(redemption_id).expect("Pending redemption not found");
+        self.assert_can_reconcile(redemption.started_at.0);
+        self.redemptions.finish_unstake(redemption_id);
+
+        // Pool Party is checked again when withdrawing, so the NEAR is withdrawable from now on
+        if succeeded {
+            self.record_redemption(&redemption.user, redemption.tokens.0, redemption.near.0, env::epoch_height());
+        } else {
+            self.refund_redemption(&redemption.user, redemption.tokens.0, redemption.near.0);
+        }
+        events::reconcile_redemption(&redemption, succeeded);
+    }
+
+    // Resolves a withdrawal from Pool Party whose callbacks never ran. The owner checks in
+    // Pool Party how much NEAR was `withdrawn` to this contract. The redemptions go back
+    // to the user, who can withdraw them again, and other withdrawals are unblocked
+    pub fn reconcile_withdrawal(&mut self, withdrawn: U128) {
+        self.assert_owner();
+
+        let withdrawal = self.redemptions.withdrawing.clone().expect("There is no withdrawal in progress");
+        self.assert_can_reconcile(withdrawal.started_at.0);
+
+        self.redemptions.withdrawing = None;
+        self.redemptions.near_withdrawn += withdrawn.0;
+        self.redemptions.restore(&withdrawal.user, withdrawal.withdrawals.clone());
+        events::reconcile_withdrawal(&withdrawal, withdrawn.0);
+    }
+
+    // Send the user the NEAR of their redemptions that finished unbonding. If Pool Party
+    // did not release it yet, the redemptions are kept and can be withdrawn later
+    pub fn withdraw_redeemed(&mut self) -> Promise {
+        let user: AccountId = env::predecessor_account_id();
+        let withdrawals = self.redemptions.take_ready(&user);
+        assert!(!withdrawals.is_empty(), "There is no redemption ready to withdraw");
+
+        // The NEAR was already withdrawn from Pool Party by another user
+        let amount = total_near(&withdrawals);
+        if self.redemptions.near_withdrawn >= amount {
+            return self.pay_redeemed(user, amount);
+        }
+
+        let required_gas = self.config.withdraw_prepaid_gas.0;
+        assert!(env::prepaid_gas() >= required_gas, "This method requires at least {} TGAS to run", required_gas / TGAS);
+        assert!(self.redemptions.withdrawing.is_none(), "A withdrawal from Pool Party is in progress, try again later");
+        let withdrawal_id = self.redemptions.start_withdrawal(&user, withdrawals);
+
+        let this: AccountId = env::current_account_id();
+        let callback_gas = self.config.withdraw_all_gas.0 + self.config.withdraw_callback_gas.0 + config::CHECK_CALLBACK_GAS;
+
+        poolparty_contract::get_account(this.clone(), &self.pool_party_account, NO_DEPOSIT, self.config.get_pool_info_gas.0)
+            .and(self.get_pool_info())
+            .then(this_contract::withdraw_redeemed_callback(withdrawal_id, &this, NO_DEPOSIT, callback_gas))
+    }
+
+    #[private]
+    pub fn withdraw_redeemed_callback(&mut self, withdrawal_id: u64) -> PromiseOrValue<bool> {
+        let withdrawal = match self.redemptions.withdrawal(withdrawal_id) {
+            Some(withdrawal) => withdrawal,
+            None => {
+                log!("Withdrawal {} was already reconciled, ignoring its callback", withdrawal_id);
+                return PromiseOrValue::Value(false);
+            }
+        };
+
+        let account = external::promise_result_json::<PoolAccount>(0);
+        let pool_info = external::promise_result_json::<PoolInfo>(1);
+
+        let unstaked = match (account, pool_info) {
+            (Ok(account), Ok(pool_info)) if account.available && pool_info.withdraw_ready => account.unstaked_balance,
+            (Ok(_), Ok(_)) => {
+                log!("Pool Party cannot release the NEAR yet, try again later");
+                return self.cancel_withdrawal(withdrawal);
+            }
+            (Err(error), _) | (_, Err(error)) => {
+                log!("Getting info from Pool Party failed: {}", error);
+                return self.cancel_withdrawal(withdrawal);
+            }
+        };
+
+        let this: AccountId = env::current_account_id();
+        poolparty_contract::withdraw_all(&self.pool_party_account, NO_DEPOSIT, self.config.withdraw_all_gas.0)
+            .then(this_contract::withdraw_all_callback(
+                withdrawal_id,
+                unstaked,
+                &this,
+                NO_DEPOSIT,
+                self.config.withdraw_callback_gas.0,
+            ))
+            .into()
+    }
+
+    #[private]
+    pub fn withdraw_all_callback(&mut self, withdrawal_id: u64, unstaked: U128) -> PromiseOrValue<bool> {
+        let withdrawal = match self.redemptions.withdrawal(withdrawal_id) {
+            Some(withdrawal) => withdrawal,
+            None => {
+                log!("Withdrawal {} was already reconciled, ignoring its callback", withdrawal_id);
+                return PromiseOrValue::Value(false);
+            }
+        };
+
+        if let Err(error) = external::promise_result(0) {
+            log!("Withdrawing from Pool Party failed: {}", error);
+            return self.cancel_withdrawal(withdrawal);
+        }
+
+        self.redemptions.withdrawing = None;
+        self.redemptions.near_withdrawn += unstaked.0;
+
+        let amount = total_near(&withdrawal.withdrawals);
+        if self.redemptions.near_withdrawn < amount {
+            log!("Pool Party released less NEAR than expected, try again later");
+            self.redemptions.restore(&withdrawal.user, withdrawal.withdrawals);
+            return PromiseOrValue::Value(false);
+        }
+
+        self.pay_redeemed(withdrawal.user, amount).into()
+    }
+}
+
+impl Contract {
+    // The NEAR was unstaked, the user can withdraw it from the epoch `available_when`
+    fn record_redemption(&mut self, user: &AccountId, tokens: Balance, near: Balance, available_when: u64) {
+        let withdrawal =
+            PendingWithdrawal { near: near.into(), tokens: tokens.into(), withdrawable_at_epoch: available_when.into() };
+        self.redemptions.push(user, withdrawal);
+        self.treasury.record_redemption(tokens, near);
+        events::redeem_tokens_for_near(user, tokens, near);
+    }
+
+    fn refund_redemption(&mut self, user: &AccountId, tokens: Balance, near: Balance) {
+        let this: AccountId = env::current_account_id();
+        self.internal_transfer(&this, user, tokens, None);
+        self.pool_party_reserve += near;
+        self.treasury.record_tokens_refund(tokens);
+        events::redeem_tokens_for_near_refund(user, tokens, near);
+    }
+
+    fn pay_redeemed(&mut self, user: AccountId, amount: Balance) -> Promise {
+        self.redemptions.near_withdrawn -= amount;
+        events::redeemed_near_withdrawn(&user, amount);
+        self.refund(user, amount)
+    }
+
+    fn cancel_withdrawal(&mut self, withdrawal: WithdrawalInProgress) -> PromiseOrValue<bool> {
+        self.redemptions.withdrawing = None;
+        self.redemptions.restore(&withdrawal.user, withdrawal.withdrawals);
+        PromiseOrValue::Value(false)
+    }
+}
@@ -0,0 +1,54 @@
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, Promise};
+
+use crate::*;
+
+// When the raffle took place the cached reserve is outdated. Instead of failing, the
+// exchanges are queued while Pool Party is asked for the new reserve, and the queue is
+// settled on the callback. If the callback fails, they wait in the queue like any other exchange
+#[near_bindgen]
+impl Contract {
+    // Settle the queue from its head, the same as `process_queue`, so the exchanges queued
+    // before this one keep their turn, and paused exchanges wait. Returns how many were settled
+    #[private]
+    pub fn exchange_after_refresh_callback(&mut self, queued_id: u64) -> u64 {
+        self.update_cache_from_promise();
+
+        if self.is_close_to_raffle() {
+            log!("The reserve is not updated for the new raffle yet, queued exchange {} waits", queued_id);
+            return 0;
+        }
+
+        let settled = self.settle_queue(u64::MAX);
+        if self.queue.get(queued_id).is_some() {
+            log!("Queued exchange {} waits for its turn, call process_queue to settle it", queued_id);
+        }
+        settled
+    }
+}
+
+impl Contract {
+    // Queue the exchange, and settle it once the cache is updated
+    pub(crate) fn refresh_then_exchange(
+        &mut self,
+        direction: ExchangeDirection,
+        user: &AccountId,
+        amount: Balance,
+        min_out: Option<U128>,
+        deadline: Option<U64>,
+    ) -> Promise {
+        let entry = self.enqueue(direction, user, amount, min_out, deadline);
+
+        let callback = this_contract::exchange_after_refresh_callback(
+            entry.id.0,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.refresh_callback_gas.0 + self.exchange_gas(direction),
+        );
+        self.get_pool_info().then(callback)
+    }
+
+    pub(crate) fn get_pool_info(&self) -> Promise {
+        poolparty_contract::get_pool_info(&self.pool_party_account, NO_DEPOSIT, self.config.get_pool_info_gas.0)
+    }
+}
@@ -0,0 +1,62 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, Balance};
+
+use crate::*;
+
+// Cumulative amounts moved by the exchanges since the contract was deployed
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Default, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryStats {
+    // NEAR staked in Pool Party by selling tokens, and the tokens sold
+    pub near_staked: U128,
+    pub tokens_sold: U128,
+    // Sale fees, paid on top of the staked NEAR
+    pub fees_collected: U128,
+    // Tokens exchanged for tickets, and the tickets given from the reserve
+    pub tokens_redeemed: U128,
+    pub tickets_given: U128,
+    // Tokens redeemed for NEAR, and the NEAR unstaked from Pool Party for them
+    pub tokens_redeemed_for_near: U128,
+    pub near_unstaked: U128,
+    // NEAR and tokens given back because an exchange did not take place
+    pub near_refunded: U128,
+    pub tokens_refunded: U128,
+}
+
+impl TreasuryStats {
+    pub fn record_sale(&mut self, near: Balance, tokens: Balance) {
+        self.near_staked.0 += near;
+        self.tokens_sold.0 += tokens;
+    }
+
+    pub fn record_fee(&mut self, fee: Balance) {
+        self.fees_collected.0 += fee;
+    }
+
+    pub fn record_tickets(&mut self, tokens: Balance, tickets: Balance) {
+        self.tokens_redeemed.0 += tokens;
+        self.tickets_given.0 += tickets;
+    }
+
+    pub fn record_redemption(&mut self, tokens: Balance, near: Balance) {
+        self.tokens_redeemed_for_near.0 += tokens;
+        self.near_unstaked.0 += near;
+    }
+
+    pub fn record_near_refund(&mut self, near: Balance) {
+        self.near_refunded.0 += near;
+    }
+
+    pub fn record_tokens_refund(&mut self, tokens: Balance) {
+        self.tokens_refunded.0 += tokens;
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_treasury_stats(&self) -> TreasuryStats {
+        self.treasury.clone()
+    }
+}
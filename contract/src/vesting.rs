@@ -0,0 +1,161 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+// Tokens released linearly between `start` and `end`, none of them before the `cliff`
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct VestingSchedule {
+    total: Balance,
+    claimed: Balance,
+    start: u64,
+    cliff: u64,
+    end: u64,
+    revocable: bool,
+}
+
+impl VestingSchedule {
+    fn vested_at(&self, timestamp: u64) -> Balance {
+        if timestamp < self.cliff {
+            0
+        } else if timestamp >= self.end {
+            self.total
+        } else {
+            let vested = U256::from(self.total) * U256::from(timestamp - self.start) / U256::from(self.end - self.start);
+            u256_to_balance(vested)
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingInfo {
+    pub total: U128,
+    pub vested: U128,
+    pub claimed: U128,
+    // Tokens not vested yet
+    pub locked: U128,
+    pub start: U64,
+    pub cliff: U64,
+    pub end: U64,
+    pub revocable: bool,
+}
+
+// One schedule per beneficiary, its tokens are escrowed by the contract until claimed
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct VestingLedger {
+    schedules: LookupMap<AccountId, VestingSchedule>,
+    pub tokens_escrowed: Balance,
+}
+
+impl VestingLedger {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self { schedules: LookupMap::new(prefix), tokens_escrowed: 0 }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_vesting(&self, account_id: ValidAccountId) -> Option<VestingInfo> {
+        self.vesting.schedules.get(account_id.as_ref()).map(|schedule| {
+            let vested = schedule.vested_at(env::block_timestamp());
+            VestingInfo {
+                total: schedule.total.into(),
+                vested: vested.into(),
+                claimed: schedule.claimed.into(),
+                locked: (schedule.total - vested).into(),
+                start: schedule.start.into(),
+                cliff: schedule.cliff.into(),
+                end: schedule.end.into(),
+                revocable: schedule.revocable,
+            }
+        })
+    }
+
+    // Escrow `amount` tokens of the owner, vested to `account_id` from `start` to `end`.
+    // Timestamps are in nanoseconds. Requires a full access key, by attaching 1 yoctoNEAR
+    #[payable]
+    pub fn create_vesting(
+        &mut self,
+        account_id: ValidAccountId,
+        amount: U128,
+        start: U64,
+        cliff: U64,
+        end: U64,
+        revocable: bool,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+
+        let account_id: AccountId = account_id.into();
+        assert!(self.vesting.schedules.get(&account_id).is_none(), "The account already has a vesting schedule");
+        assert!(self.token.accounts.contains_key(&account_id), "The beneficiary is not registered");
+        assert!(amount.0 > 0, "The vesting schedule must have tokens");
+        assert!(start.0 <= cliff.0 && cliff.0 <= end.0 && start.0 < end.0,
+                "The vesting must start before the cliff and end after it");
+
+        let owner_id = self.owner_id.clone();
+        self.internal_transfer(&owner_id, &env::current_account_id(), amount.0, Some("vesting".to_string()));
+
+        let schedule =
+            VestingSchedule { total: amount.0, claimed: 0, start: start.0, cliff: cliff.0, end: end.0, revocable };
+        self.vesting.schedules.insert(&account_id, &schedule);
+        self.vesting.tokens_escrowed += amount.0;
+    }
+
+    // Send the vested tokens that were not claimed yet to the beneficiary
+    pub fn claim_vested(&mut self) -> U128 {
+        let user: AccountId = env::predecessor_account_id();
+        let mut schedule = self.vesting.schedules.get(&user).expect("The account has no vesting schedule");
+
+        let amount = schedule.vested_at(env::block_timestamp()) - schedule.claimed;
+        assert!(amount > 0, "There are no vested tokens to claim");
+
+        schedule.claimed += amount;
+        if schedule.claimed == schedule.total {
+            self.vesting.schedules.remove(&user);
+        } else {
+            self.vesting.schedules.insert(&user, &schedule);
+        }
+        self.vesting.tokens_escrowed -= amount;
+
+        self.internal_transfer(&env::current_account_id(), &user, amount, Some("vesting".to_string()));
+        amount.into()
+    }
+
+    // Stop a revocable schedule, the tokens vested so far can still be claimed,
+    // and the unvested ones go back to the owner
+    pub fn terminate_vesting(&mut self, account_id: ValidAccountId) -> U128 {
+        self.assert_owner();
+
+        let account_id: AccountId = account_id.into();
+        let mut schedule = self.vesting.schedules.get(&account_id).expect("The account has no vesting schedule");
+        assert!(schedule.revocable, "The vesting schedule is not revocable");
+
+        let now = env::block_timestamp();
+        let vested = schedule.vested_at(now);
+        let unvested = schedule.total - vested;
+
+        // End the schedule now, with only the tokens vested so far. Before the cliff
+        // nothing is vested, and the schedule is removed
+        schedule.total = vested;
+        schedule.end = schedule.end.min(now);
+        schedule.revocable = false;
+        if schedule.claimed == schedule.total {
+            self.vesting.schedules.remove(&account_id);
+        } else {
+            self.vesting.schedules.insert(&account_id, &schedule);
+        }
+        self.vesting.tokens_escrowed -= unvested;
+
+        let owner_id = self.owner_id.clone();
+        if unvested > 0 {
+            self.internal_transfer(&env::current_account_id(), &owner_id, unvested, Some("vesting".to_string()));
+        }
+        log!("Vesting of {} terminated, {} tokens returned to {}", &account_id, unvested, &owner_id);
+        unvested.into()
+    }
+}
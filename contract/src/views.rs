@@ -0,0 +1,101 @@
+use near_sdk::json_types::{U128, U64};
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Quote {
+    pub amount_in: U128,
+    pub amount_out: U128,
+    // Sale fee taken from `amount_in`, only for NEAR
+    pub fee: U128,
+    // yoctoNEAR (or tickets) per token unit, on average for this exchange, and
+    // for the next token unit after it
+    pub average_price: U128,
+    pub marginal_price: U128,
+    // The exchange would be rejected because the raffle is close, or the cache is outdated
+    pub close_to_raffle: bool,
+    // The exchange would be rejected because there is not enough to give back
+    pub insufficient_inventory: bool,
+    // The exchange would be rejected by the pricing, e.g. the reserve is empty or the amount buys nothing
+    pub pricing_error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ExchangeState {
+    // Cached reserve of Pool Party, and when the cache was last refreshed
+    pub reserve: U128,
+    pub reserve_updated_at: U64,
+    // None if the reserve was never cached
+    pub next_raffle: Option<U64>,
+    // Exchanges are rejected from this moment until the cache is updated after the raffle
+    pub exchange_closes_at: Option<U64>,
+    // NEAR of the sales in flight, added to the reserve once Pool Party staked it
+    pub near_being_staked: U128,
+    // NEAR and tokens held for the exchanges in the queue
+    pub queued_near: U128,
+    pub queued_tokens: U128,
+    pub contract_tokens: U128,
+    pub circulating_supply: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_exchange_state(&self) -> ExchangeState {
+        ExchangeState {
+            reserve: self.pool_party_reserve.into(),
+            reserve_updated_at: self.pool_party_reserve_updated_at.into(),
+            next_raffle: self.pool_party_next_raffle.map(U64),
+            exchange_closes_at: self.exchange_closes_at().map(U64),
+            near_being_staked: self.pending.near_in.into(),
+            queued_near: self.queue.near_escrowed.into(),
+            queued_tokens: self.queue.tokens_escrowed.into(),
+            contract_tokens: self.tokens_own_by_contract().into(),
+            circulating_supply: self.circulating_supply().into(),
+        }
+    }
+
+    // How many tokens would `exchange_near_for_tokens` give for `near_amount`, fee included
+    pub fn quote_near_for_tokens(&self, near_amount: U128) -> Quote {
+        let fee = self.sale_fee(near_amount.0);
+        // Without a price, the quote only reports the rejection
+        let (token_amount, pricing_error) = match self.tokens_for_near(near_amount.0 - fee) {
+            Ok(token_amount) => (token_amount, None),
+            Err(error) => (0, Some(error)),
+        };
+        let marginal_price = match self.pricing {
+            PricingMode::Flat => self.marginal_price(),
+            _ => self.pricing.price_at(self.tokens_sold() + token_amount, self.token.total_supply),
+        };
+
+        Quote {
+            amount_in: near_amount,
+            amount_out: token_amount.into(),
+            fee: fee.into(),
+            average_price: (near_amount.0 - fee).checked_div(token_amount).unwrap_or(0).into(),
+            marginal_price: marginal_price.into(),
+            close_to_raffle: self.is_close_to_raffle(),
+            insufficient_inventory: token_amount > self.tokens_for_sale(),
+            pricing_error,
+        }
+    }
+
+    // How many tickets would `exchange_tokens_for_tickets` give for `amount_tokens`
+    pub fn quote_tokens_for_tickets(&self, amount_tokens: U128) -> Quote {
+        let amount_tickets = self.tickets_for_tokens(amount_tokens.0, self.circulating_supply());
+
+        Quote {
+            amount_in: amount_tokens,
+            amount_out: amount_tickets.into(),
+            fee: U128(0),
+            average_price: amount_tickets.checked_div(amount_tokens.0).unwrap_or(0).into(),
+            marginal_price: self.pool_party_reserve.checked_div(self.circulating_supply()).unwrap_or(0).into(),
+            close_to_raffle: self.is_close_to_raffle(),
+            insufficient_inventory: amount_tickets > self.pool_party_reserve,
+            pricing_error: None,
+        }
+    }
+}
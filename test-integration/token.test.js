@@ -25,7 +25,7 @@ describe('Pool Token', function () {
     function create_contract_token(user){
       return near.loadContract(token_contract, {
         viewMethods: ['ft_balance_of'],
-        changeMethods: ['new',
+        changeMethods: ['new_default_meta',
                         'cache_pool_party_reserve',
                         'cache_pool_party_reserve_callback',
                         'exchange_tokens_for_tickets', 
@@ -129,7 +129,8 @@ describe('Pool Token', function () {
 
   describe('TOKENs', function () {
     it("starts", async function(){
-      await token_A.new()
+      await token_A.new_default_meta({owner_id: user_A, total_supply: "10000000",
+                                     pool_party_account: pool_party_contract, cache_reserve: true})
       await cache_pool_party_reserve()
       await raffle()
     })
@@ -15,6 +15,8 @@ near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
 
 const FT_ID: &str = "ft";
 const DEFI_ID: &str = "defi";
+// Pool Party is not deployed, the exchanges are not simulated
+const POOL_PARTY_ID: &str = "pool_party";
 
 // Register the given `user` with FT contract
 pub fn register_user(user: &near_sdk_sim::UserAccount) {
@@ -43,6 +45,7 @@ pub fn init_no_macros(initial_balance: u128) -> (UserAccount, UserAccount, UserA
         &json!({
             "owner_id": root.valid_account_id(),
             "total_supply": U128::from(initial_balance),
+            "pool_party_account": POOL_PARTY_ID,
         })
         .to_string()
         .into_bytes(),